@@ -0,0 +1,42 @@
+// Inserts lines one at a time at the top of a buffer, the worst case for a
+// `Vec<String>`, and times it against the rope-backed `TextBuffer`.
+//
+// Run with `cargo bench`.
+
+use std::time::Instant;
+
+// The crate is a binary only, so pull the module in directly
+#[allow(dead_code)]
+#[path = "../src/text.rs"]
+mod text;
+
+use text::TextBuffer;
+
+const LINES: usize = 100_000;
+
+fn main() {
+    let start = Instant::now();
+    let mut lines = vec![String::new()];
+    for i in 0..LINES {
+        lines.insert(0, format!("line {}", i));
+    }
+    let vec_time = start.elapsed();
+    assert_eq!(lines.len(), LINES + 1);
+
+    let start = Instant::now();
+    let mut buffer = TextBuffer::default();
+    for i in 0..LINES {
+        buffer.insert_lines(0, &[format!("line {}", i)]);
+    }
+    let rope_time = start.elapsed();
+    assert_eq!(buffer.line_count(), LINES + 1);
+
+    println!(
+        "Vec<String>: {:>10.2?} for {} inserts at the top",
+        vec_time, LINES
+    );
+    println!(
+        "TextBuffer:  {:>10.2?} for {} inserts at the top",
+        rope_time, LINES
+    );
+}
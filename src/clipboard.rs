@@ -0,0 +1,25 @@
+use arboard::Clipboard;
+
+// The system clipboard, connected to on first use. Kept open for the life of
+// the editor since on X11 the text we copy is only served while it is.
+#[derive(Default)]
+pub struct SystemClipboard {
+    inner: Option<Clipboard>,
+}
+
+impl SystemClipboard {
+    pub fn get_text(&mut self) -> Result<String, String> {
+        self.connect()?.get_text().map_err(|e| e.to_string())
+    }
+
+    pub fn set_text(&mut self, text: String) -> Result<(), String> {
+        self.connect()?.set_text(text).map_err(|e| e.to_string())
+    }
+
+    fn connect(&mut self) -> Result<&mut Clipboard, String> {
+        if self.inner.is_none() {
+            self.inner = Some(Clipboard::new().map_err(|e| e.to_string())?);
+        }
+        Ok(self.inner.as_mut().unwrap())
+    }
+}
@@ -0,0 +1,122 @@
+use crate::keymap::KeyBindings;
+use serde::Deserialize;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Default,
+    // No syntax colors
+    Mono,
+}
+
+// Preferences read from the config file; keys left out keep their default
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub tab_width: usize,
+    pub expand_tabs: bool,
+    // With `expand_tabs`, Backspace in indentation deletes back to the
+    // previous tab stop
+    pub smarttab: bool,
+    pub show_line_numbers: bool,
+    pub auto_indent: bool,
+    pub theme: Theme,
+    // A bar cursor in Insert mode and a block elsewhere, for terminals that
+    // can change it
+    pub cursor_shape: bool,
+    // Seconds of idle time before unsaved changes are written to a swap
+    // file; 0 turns swap files off
+    pub swap_interval: u64,
+    // Two chars, like "jk", that leave Insert mode when typed within
+    // `escape_timeout` milliseconds of each other; empty turns this off
+    pub escape_sequence: String,
+    pub escape_timeout: u64,
+    // Strip trailing spaces and tabs, and blank lines at the end of the file,
+    // when saving
+    pub trim_trailing_whitespace: bool,
+    // Enter on a `- item` or `1. item` line starts the next item
+    pub continue_lists: bool,
+    // strftime-style patterns for the text `:date` and `:time` insert
+    pub date_format: String,
+    pub time_format: String,
+    // `[keys.normal]` and `[keys.insert]` tables binding keys to actions
+    pub keys: KeyBindings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tab_width: 4,
+            expand_tabs: false,
+            smarttab: false,
+            show_line_numbers: true,
+            auto_indent: false,
+            theme: Theme::Default,
+            cursor_shape: true,
+            swap_interval: 0,
+            escape_sequence: String::new(),
+            escape_timeout: 300,
+            trim_trailing_whitespace: false,
+            continue_lists: false,
+            date_format: "%Y-%m-%d".to_string(),
+            time_format: "%H:%M".to_string(),
+            keys: KeyBindings::default(),
+        }
+    }
+}
+
+impl Config {
+    // Load the config file, falling back to the defaults when it is missing.
+    // A file that can't be used also gives the defaults, along with a warning
+    // to show the user.
+    pub fn load() -> (Config, Option<String>) {
+        let Some(path) = config_path() else {
+            return (Config::default(), None);
+        };
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == ErrorKind::NotFound => return (Config::default(), None),
+            Err(e) => return (Config::default(), Some(format!("Config error: {}", e))),
+        };
+        match toml::from_str::<Config>(&text) {
+            // A zero tab width would break tab stop maths
+            Ok(config) if config.tab_width == 0 => (
+                Config::default(),
+                Some("Config error: tab_width must be at least 1".to_string()),
+            ),
+            Ok(config)
+                if !config.escape_sequence.is_empty()
+                    && config.escape_sequence.chars().count() != 2 =>
+            {
+                (
+                    Config::default(),
+                    Some("Config error: escape_sequence must be two characters".to_string()),
+                )
+            }
+            Ok(config) => match config.keys.check() {
+                Some(reason) => (Config::default(), Some(format!("Config error: {}", reason))),
+                None => (config, None),
+            },
+            Err(e) => {
+                let reason = e.message().to_string();
+                (Config::default(), Some(format!("Config error: {}", reason)))
+            }
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/rust-text-editor, or under ~/.config
+pub fn config_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("rust-text-editor"))
+}
+
+fn config_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("config.toml"))
+}
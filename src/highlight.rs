@@ -0,0 +1,125 @@
+use crossterm::style::Color;
+use std::path::Path;
+
+const RUST_KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Syntax {
+    Rust,
+}
+
+impl Syntax {
+    pub fn from_path(path: &Path) -> Option<Syntax> {
+        match path.extension()?.to_str()? {
+            "rs" => Some(Syntax::Rust),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenKind {
+    Normal,
+    Keyword,
+    String,
+    Comment,
+    Number,
+}
+
+impl TokenKind {
+    pub fn color(self) -> Option<Color> {
+        match self {
+            TokenKind::Normal => None,
+            TokenKind::Keyword => Some(Color::Yellow),
+            TokenKind::String => Some(Color::Green),
+            TokenKind::Comment => Some(Color::DarkGrey),
+            TokenKind::Number => Some(Color::Cyan),
+        }
+    }
+}
+
+// Kind of each char of `line`. Lines are highlighted on their own, so
+// strings and block comments don't carry over to the next line.
+pub fn highlight_line(line: &str, syntax: Syntax) -> Vec<TokenKind> {
+    match syntax {
+        Syntax::Rust => highlight_rust(line),
+    }
+}
+
+fn highlight_rust(line: &str) -> Vec<TokenKind> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut kinds = vec![TokenKind::Normal; chars.len()];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let start = i;
+
+        let kind = if c == '/' && next == Some('/') {
+            i = chars.len();
+            TokenKind::Comment
+        } else if c == '/' && next == Some('*') {
+            i += 2;
+            while i < chars.len() && !(chars[i] == '*' && chars.get(i + 1) == Some(&'/')) {
+                i += 1;
+            }
+            i = (i + 2).min(chars.len());
+            TokenKind::Comment
+        } else if c == '"' {
+            i += 1;
+            while i < chars.len() && chars[i] != '"' {
+                i += if chars[i] == '\\' { 2 } else { 1 };
+            }
+            i = (i + 1).min(chars.len());
+            TokenKind::String
+        } else if let Some(len) = char_literal_len(&chars[i..]) {
+            // Anything else starting with a quote is a lifetime
+            i += len;
+            TokenKind::String
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_alphanumeric() || matches!(chars[i], '_' | '.')) {
+                // Stop before a range like `0..10` or a method call like `1.max(2)`
+                if chars[i] == '.' && !chars.get(i + 1).is_some_and(char::is_ascii_digit) {
+                    break;
+                }
+                i += 1;
+            }
+            TokenKind::Number
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            if RUST_KEYWORDS.contains(&word.as_str()) {
+                TokenKind::Keyword
+            } else {
+                TokenKind::Normal
+            }
+        } else {
+            i += 1;
+            TokenKind::Normal
+        };
+
+        kinds[start..i].fill(kind);
+    }
+    kinds
+}
+
+// Length of a char literal like 'a' or '\n' at the start of `chars`
+fn char_literal_len(chars: &[char]) -> Option<usize> {
+    if chars.first() != Some(&'\'') {
+        return None;
+    }
+    let end = if chars.get(1) == Some(&'\\') {
+        chars.iter().skip(3).position(|&c| c == '\'')? + 3
+    } else {
+        2
+    };
+    (chars.get(end) == Some(&'\'')).then_some(end + 1)
+}
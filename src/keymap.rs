@@ -0,0 +1,314 @@
+use crate::Mode;
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// What a key does in Normal or Insert mode. Keys that start a multi-key
+// command (like `d` or `g`) are bound here, but the keys completing them are
+// not.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    // Motions, which Visual mode shares
+    MoveLeft,
+    MoveDown,
+    MoveUp,
+    MoveRight,
+    WordForward,
+    WordBackward,
+    ParagraphForward,
+    ParagraphBackward,
+    ScrollHalfDown,
+    ScrollHalfUp,
+    ScrollPageDown,
+    ScrollPageUp,
+    LineStart,
+    LineEnd,
+    FirstNonBlank,
+    GotoLine,
+    MatchBracket,
+    FindForward,
+    FindBackward,
+    TillForward,
+    TillBackward,
+    RepeatFind,
+    RepeatFindReverse,
+    Fold,
+    GotoMark,
+    GotoMarkLine,
+
+    // Normal mode commands
+    Save,
+    Quit,
+    Undo,
+    Redo,
+    JumpBack,
+    JumpForward,
+    ShowStats,
+    Record,
+    PlayMacro,
+    SetMark,
+    Insert,
+    Append,
+    AppendAtEnd,
+    OpenBelow,
+    OpenAbove,
+    CommandLine,
+    Search,
+    SearchNext,
+    SearchPrevious,
+    SearchWordForward,
+    SearchWordBackward,
+    Visual,
+    VisualLine,
+    VisualBlock,
+    DeleteChar,
+    ToggleCase,
+    ReplaceMode,
+    RepeatChange,
+    DeleteToEnd,
+    ChangeToEnd,
+    ChangeLines,
+    Goto,
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Dedent,
+    ReplaceChar,
+    Register,
+    JoinLines,
+    PasteAfter,
+    PasteBefore,
+
+    // Insert mode commands
+    LeaveInsert,
+    Backspace,
+    Newline,
+    Tab,
+    DeleteWordBackward,
+    DeleteToLineStart,
+    CompleteNext,
+    CompletePrevious,
+}
+
+impl Action {
+    pub fn is_insert(self) -> bool {
+        matches!(
+            self,
+            Action::LeaveInsert
+                | Action::Backspace
+                | Action::Newline
+                | Action::Tab
+                | Action::DeleteWordBackward
+                | Action::DeleteToLineStart
+                | Action::CompleteNext
+                | Action::CompletePrevious
+        )
+    }
+}
+
+const NORMAL_KEYS: &[(&str, Action)] = &[
+    ("h", Action::MoveLeft),
+    ("j", Action::MoveDown),
+    ("k", Action::MoveUp),
+    ("l", Action::MoveRight),
+    ("w", Action::WordForward),
+    ("b", Action::WordBackward),
+    ("}", Action::ParagraphForward),
+    ("{", Action::ParagraphBackward),
+    ("<C-d>", Action::ScrollHalfDown),
+    ("<C-u>", Action::ScrollHalfUp),
+    ("<C-f>", Action::ScrollPageDown),
+    ("<C-b>", Action::ScrollPageUp),
+    ("0", Action::LineStart),
+    ("$", Action::LineEnd),
+    ("^", Action::FirstNonBlank),
+    ("G", Action::GotoLine),
+    ("%", Action::MatchBracket),
+    ("f", Action::FindForward),
+    ("F", Action::FindBackward),
+    ("t", Action::TillForward),
+    ("T", Action::TillBackward),
+    (";", Action::RepeatFind),
+    (",", Action::RepeatFindReverse),
+    ("z", Action::Fold),
+    ("`", Action::GotoMark),
+    ("'", Action::GotoMarkLine),
+    ("<C-s>", Action::Save),
+    ("<C-q>", Action::Quit),
+    ("u", Action::Undo),
+    ("<C-r>", Action::Redo),
+    ("<C-o>", Action::JumpBack),
+    // Terminals send Ctrl-I as Tab
+    ("<Tab>", Action::JumpForward),
+    ("<C-i>", Action::JumpForward),
+    ("<C-g>", Action::ShowStats),
+    ("q", Action::Record),
+    ("@", Action::PlayMacro),
+    ("m", Action::SetMark),
+    ("i", Action::Insert),
+    ("a", Action::Append),
+    ("A", Action::AppendAtEnd),
+    ("o", Action::OpenBelow),
+    ("O", Action::OpenAbove),
+    (":", Action::CommandLine),
+    ("/", Action::Search),
+    ("n", Action::SearchNext),
+    ("N", Action::SearchPrevious),
+    ("*", Action::SearchWordForward),
+    ("#", Action::SearchWordBackward),
+    ("v", Action::Visual),
+    ("V", Action::VisualLine),
+    ("<C-v>", Action::VisualBlock),
+    ("x", Action::DeleteChar),
+    ("~", Action::ToggleCase),
+    ("R", Action::ReplaceMode),
+    (".", Action::RepeatChange),
+    ("D", Action::DeleteToEnd),
+    ("C", Action::ChangeToEnd),
+    ("S", Action::ChangeLines),
+    ("g", Action::Goto),
+    ("d", Action::Delete),
+    ("c", Action::Change),
+    ("y", Action::Yank),
+    (">", Action::Indent),
+    ("<", Action::Dedent),
+    ("r", Action::ReplaceChar),
+    ("\"", Action::Register),
+    ("J", Action::JoinLines),
+    ("p", Action::PasteAfter),
+    ("P", Action::PasteBefore),
+];
+
+const INSERT_KEYS: &[(&str, Action)] = &[
+    ("<Esc>", Action::LeaveInsert),
+    ("<BS>", Action::Backspace),
+    ("<CR>", Action::Newline),
+    ("<Tab>", Action::Tab),
+    ("<C-w>", Action::DeleteWordBackward),
+    ("<C-u>", Action::DeleteToLineStart),
+    ("<C-n>", Action::CompleteNext),
+    ("<C-p>", Action::CompletePrevious),
+];
+
+// Bindings from the config file, by mode; keys are written like `x`, `<C-s>`
+// or `<Esc>`, and actions like `move_left`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct KeyBindings {
+    pub normal: HashMap<String, Action>,
+    pub insert: HashMap<String, Action>,
+}
+
+impl KeyBindings {
+    // Why the bindings can't be used, if they can't
+    pub fn check(&self) -> Option<String> {
+        for (mode, bindings, insert) in [
+            ("normal", &self.normal, false),
+            ("insert", &self.insert, true),
+        ] {
+            for (key, action) in bindings {
+                if parse_key(key).is_none() {
+                    return Some(format!("unknown key {:?}", key));
+                }
+                if action.is_insert() != insert {
+                    return Some(format!(
+                        "{:?} can't be bound to that action in {} mode",
+                        key, mode
+                    ));
+                }
+            }
+        }
+        None
+    }
+}
+
+pub struct Keymap {
+    bindings: HashMap<(Mode, KeyCode, KeyModifiers), Action>,
+}
+
+impl Keymap {
+    // The default bindings, with `overrides` on top
+    pub fn new(overrides: &KeyBindings) -> Self {
+        let mut keymap = Keymap {
+            bindings: HashMap::new(),
+        };
+        let defaults = NORMAL_KEYS
+            .iter()
+            .map(|&(key, action)| (Mode::Normal, key, action))
+            .chain(
+                INSERT_KEYS
+                    .iter()
+                    .map(|&(key, action)| (Mode::Insert, key, action)),
+            );
+        let custom = [
+            (Mode::Normal, &overrides.normal),
+            (Mode::Insert, &overrides.insert),
+        ]
+        .into_iter()
+        .flat_map(|(mode, bindings)| {
+            bindings
+                .iter()
+                .map(move |(key, &action)| (mode, key.as_str(), action))
+        });
+        for (mode, key, action) in defaults.chain(custom) {
+            // Bad keys in the config are reported when it's loaded
+            if let Some((code, modifiers)) = parse_key(key) {
+                keymap.bindings.insert((mode, code, modifiers), action);
+            }
+        }
+        keymap
+    }
+
+    pub fn get(&self, mode: Mode, key: KeyEvent) -> Option<Action> {
+        let mut modifiers = key.modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT);
+        if !matches!(key.code, KeyCode::Char(_)) {
+            modifiers |= key.modifiers & KeyModifiers::SHIFT;
+        }
+        self.bindings.get(&(mode, key.code, modifiers)).copied()
+    }
+}
+
+// A single char, or a Vim-style name in angle brackets like `<C-x>`,
+// `<Esc>`, `<CR>` or `<BS>`
+fn parse_key(key: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let mut chars = key.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Some((KeyCode::Char(c), KeyModifiers::NONE));
+    }
+    let name = key.strip_prefix('<')?.strip_suffix('>')?;
+    let (modifiers, name) = match name.split_once('-') {
+        Some(("C", rest)) if !rest.is_empty() => (KeyModifiers::CONTROL, rest),
+        Some(("A" | "M", rest)) if !rest.is_empty() => (KeyModifiers::ALT, rest),
+        _ => (KeyModifiers::NONE, name),
+    };
+    let code = match name.to_ascii_lowercase().as_str() {
+        "esc" => KeyCode::Esc,
+        "cr" | "enter" => KeyCode::Enter,
+        "bs" => KeyCode::Backspace,
+        "tab" => KeyCode::Tab,
+        "space" => KeyCode::Char(' '),
+        "lt" => KeyCode::Char('<'),
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "del" => KeyCode::Delete,
+        _ => {
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                // Ctrl and Alt with a letter, which terminals send lowercase
+                (Some(c), None) if modifiers != KeyModifiers::NONE => {
+                    KeyCode::Char(c.to_ascii_lowercase())
+                }
+                _ => return None,
+            }
+        }
+    };
+    Some((code, modifiers))
+}
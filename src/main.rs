@@ -1,184 +1,5147 @@
+use chrono::Local;
 use crossterm::{
     cursor,
-    event::{self, Event, KeyCode, KeyEvent},
-    execute,
+    event::{
+        self, DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+        Event, KeyCode, KeyEvent, KeyModifiers, MouseButton, MouseEvent, MouseEventKind,
+    },
+    execute, queue,
+    style::{Attribute, Color, Print, SetAttribute, SetBackgroundColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
-use std::io::{stdout, Result, Write};
-use unicode_width::UnicodeWidthStr;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{stdin, stdout, ErrorKind, IsTerminal, Read, Result, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::{Duration, Instant};
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+mod clipboard;
+mod config;
+mod highlight;
+mod keymap;
+mod recent;
+mod text;
+
+use clipboard::SystemClipboard;
+use config::{Config, Theme};
+use highlight::{highlight_line, Syntax};
+use keymap::{Action, Keymap};
+use text::{LineShift, Marker, TextBuffer};
+
+// Maximum number of undo steps kept in memory
+const UNDO_LIMIT: usize = 1000;
+
+// Maximum number of positions kept in a buffer's jump list
+const JUMP_LIMIT: usize = 100;
+
+// Matches counted for the `[3/17]` search position before giving up and
+// showing `[3/500+]`
+const MATCH_COUNT_LIMIT: usize = 500;
+
+// Most lines, or bytes of a charwise register, one paste may put in
+const PASTE_LIMIT: usize = 10_000_000;
+
+// Lines moved per scroll wheel step
+const SCROLL_LINES: usize = 3;
+
+// Deepest a macro may play other macros, including itself
+const MACRO_DEPTH_LIMIT: usize = 100;
+
+// How long a status message stays on screen
+const STATUS_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Background of the search match the cursor last jumped to, brighter than
+// the yellow of the others
+const CURRENT_MATCH_BG: Color = Color::AnsiValue(208);
+
+// Background of the cursor line with `:set cursorline`
+const CURSORLINE_BG: Color = Color::AnsiValue(236);
+
+// Background of the `:set colorcolumn` ruler, a little lighter than the
+// cursor line so it shows across it
+const COLORCOLUMN_BG: Color = Color::AnsiValue(238);
+
+// How often timers are checked while waiting for input
+const TICK: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum Mode {
     Normal,
     Insert,
+    // `R`: typing overwrites chars instead of inserting
+    Replace,
+    SaveAs,
+    Command,
+    Search,
+    Visual,
+    VisualLine,
+    VisualBlock,
 }
 
-struct Editor {
-    content: Vec<String>,
+impl Mode {
+    fn is_visual(self) -> bool {
+        matches!(self, Mode::Visual | Mode::VisualLine | Mode::VisualBlock)
+    }
+}
+
+// First key of a multi-key Normal mode command, waiting for the next key
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pending {
+    Delete,
+    Change,
+    Yank,
+    Goto,
+    Replace,
+    // `"`, waiting for the register name
+    Register,
+    // `q` and `@`, waiting for the macro register, a letter or digit
+    Record,
+    Play,
+    // `m`, and the backtick or quote jumping to a mark
+    SetMark,
+    GotoMark,
+    GotoMarkLine,
+    // `di` and `ci`, waiting for the text object's delimiter
+    DeleteInner,
+    ChangeInner,
+    // `f`, `t`, `F` and `T`, waiting for the char to find
+    Find { forward: bool, till: bool },
+    // `dg`, waiting for the second `g`
+    DeleteGoto,
+    // `>` and `<`, waiting for the second one
+    Indent,
+    Dedent,
+    // `z`, waiting for the fold command
+    Fold,
+}
+
+// Yanked or deleted text; line-wise text is pasted as whole lines, char-wise
+// text (which may span lines) is pasted inline
+#[derive(Debug, Clone, PartialEq)]
+enum Register {
+    Lines(Vec<String>),
+    Chars(String),
+}
+
+// Vim's word boundaries fall wherever the class of char changes
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CharClass {
+    Whitespace,
+    Punctuation,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+enum LineEnding {
+    #[default]
+    Lf,
+    CrLf,
+}
+
+impl LineEnding {
+    fn as_str(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+}
+
+// Terminal cursor shape, showing which mode keys go to
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CursorShape {
+    Block,
+    Bar,
+    Underline,
+}
+
+impl CursorShape {
+    fn for_mode(mode: Mode) -> Self {
+        match mode {
+            Mode::Insert | Mode::SaveAs | Mode::Command | Mode::Search => CursorShape::Bar,
+            Mode::Replace => CursorShape::Underline,
+            _ => CursorShape::Block,
+        }
+    }
+
+    fn style(self) -> cursor::SetCursorStyle {
+        match self {
+            CursorShape::Block => cursor::SetCursorStyle::SteadyBlock,
+            CursorShape::Bar => cursor::SetCursorStyle::SteadyBar,
+            CursorShape::Underline => cursor::SetCursorStyle::SteadyUnderScore,
+        }
+    }
+}
+
+// The last `f`, `t`, `F` or `T`, repeated by `;` and `,`
+#[derive(Debug, Clone, Copy)]
+struct CharFind {
+    target: char,
+    forward: bool,
+    // Stop just before the char instead of on it
+    till: bool,
+}
+
+// A Visual selection kept for `gv`
+#[derive(Debug, Clone, Copy)]
+struct Selection {
+    mode: Mode,
+    anchor: (usize, usize),
+    cursor: (usize, usize),
+}
+
+// Words from the buffer that complete the one being typed, and which of
+// them, if any, is in its place
+#[derive(Debug, Clone)]
+struct Completion {
+    // Where the word starts, and the part typed before completing
+    start: (usize, usize),
+    prefix: String,
+    candidates: Vec<String>,
+    index: Option<usize>,
+    // Where the cursor was left; typing anything else ends the completion
+    end: (usize, usize),
+}
+
+// How a cell of the text area is drawn
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct CellStyle {
+    fg: Option<Color>,
+    bg: Option<Color>,
+    reverse: bool,
+    dim: bool,
+}
+
+#[derive(Clone)]
+struct EditorSnapshot {
+    content: TextBuffer,
     cursor_x: usize,
     cursor_y: usize,
+    // The view too, so undo doesn't jump the screen around
+    row_offset: usize,
+    col_offset: usize,
+}
+
+struct Editor {
+    // The buffer being edited; `buffers` holds the other open ones, with
+    // `buf` sitting at index `active` of the full list
+    buf: Buffer,
+    buffers: Vec<Buffer>,
+    active: usize,
+    screen_cols: usize,
+    screen_rows: usize,
+    tab_width: usize,
+    // Insert spaces instead of a tab character for the Tab key
+    expand_tabs: bool,
+    // Backspace in space indentation deletes a whole indent level
+    smarttab: bool,
+    // New lines start with the indentation of the line they were split from
+    auto_indent: bool,
+    // New lines after a list item start the next item
+    continue_lists: bool,
+    show_line_numbers: bool,
+    // Show distances from the cursor line instead of absolute numbers
+    relative_numbers: bool,
+    // Break long lines across screen rows instead of scrolling sideways
+    wrap: bool,
+    // Show tabs and trailing whitespace as visible glyphs
+    list_mode: bool,
+    // Highlight the line the cursor is on
+    cursorline: bool,
+    // 1-based display column tinted on every line as a length guide
+    colorcolumn: Option<usize>,
+    // Lines kept visible above and below the cursor when scrolling
+    scrolloff: usize,
     mode: Mode,
+    // Where the Visual mode selection started; the cursor is the other end
+    sel_x: usize,
+    sel_y: usize,
+    pending: Option<Pending>,
+    // Lines still to get the text typed after a block `I`, the block's left
+    // column, and where the typing started on the top line
+    pending_block_insert: Option<(Range<usize>, usize, usize)>,
+    // What each char typed in Replace mode overwrote, or `None` where it was
+    // added past the end of the line, so Backspace can put it back
+    replaced: Vec<Option<char>>,
+    // Ctrl-N and Ctrl-P completion in progress, while the next key might
+    // carry on cycling through it
+    completion: Option<Completion>,
+    // Set by vertical motions so the move doesn't reset `preferred_col`
+    keep_preferred_col: bool,
+    // Repeat count typed before a command, e.g. the 5 in `5j`
+    pending_count: Option<usize>,
+    // Count typed after an operator, e.g. the 3 in `2d3w`; the motion runs
+    // the two counts multiplied
+    operator_count: Option<usize>,
+    // What each key does in Normal and Insert mode
+    keymap: Keymap,
+    // Keys of the half typed command, shown next to the ruler like Vim's
+    // showcmd
+    showcmd: String,
     quit: bool,
+    theme: Theme,
+    quit_pending: bool,
+    prompt_buffer: String,
+    command_buffer: String,
+    // Commands run so far, and which one Up and Down have brought back along
+    // with what was typed before
+    command_history: Vec<String>,
+    history_index: Option<(usize, String)>,
+    last_search: Option<String>,
+    last_find: Option<CharFind>,
+    // Searches in lowercase ignore case
+    smartcase: bool,
+    // Whether matches of `last_search` are highlighted; `:noh` turns it off
+    // until the next search
+    highlight_matches: bool,
+    register: Register,
+    // Set by `"+` so the next yank, delete or paste goes through the system
+    // clipboard
+    use_clipboard: bool,
+    clipboard: SystemClipboard,
+    // Recorded key sequences by register, the register being recorded into
+    // with the keys so far, and the last one played for `@@`
+    macros: HashMap<char, Vec<KeyEvent>>,
+    recording: Option<(char, Vec<KeyEvent>)>,
+    last_macro: Option<char>,
+    // How many macros are playing inside each other, to stop runaway
+    // recursion
+    macro_depth: usize,
+    // Keys of the Normal mode command being typed, whether it stayed in modes
+    // that `.` can repeat, and the edit count when it started
+    change_keys: Vec<KeyEvent>,
+    change_repeatable: bool,
+    change_started_at: usize,
+    // Keys of the last command that changed the buffer, replayed by `.`
+    last_change: Vec<KeyEvent>,
+    // Bumped by every undoable edit
+    edits: usize,
+    // Two chars that leave Insert mode when typed quickly one after the
+    // other, and the last char typed in Insert mode with when it was typed
+    escape_sequence: Option<(char, char)>,
+    escape_timeout: Duration,
+    last_typed: Option<(char, Instant)>,
+    // Idle time after which a dirty buffer is written to its swap file,
+    // whether there was input since the last swap write, and when that was
+    swap_interval: Option<Duration>,
+    swap_due: bool,
+    last_input: Instant,
+    // Swap file found on startup, waiting for the user to pick what to do
+    recovery: Option<PathBuf>,
+    // Trim trailing whitespace from the buffer before writing it
+    trim_trailing_whitespace: bool,
+    // Default patterns for `:date` and `:time`
+    date_format: String,
+    time_format: String,
+    // Change the cursor shape with the mode, and the shape last set
+    cursor_shapes: bool,
+    cursor_shape: Option<CursorShape>,
+    // Transient message for the status line and when it was set
+    status_message: Option<(String, Instant)>,
+    // What each text row showed last frame, so only changed rows get redrawn;
+    // emptied to force a full redraw
+    frame: Vec<Vec<u8>>,
 }
 
-impl Editor {
+// An open file with its own cursor, view and undo history
+#[derive(Default)]
+struct Buffer {
+    content: TextBuffer,
+    // Char index into the current line; use `display_col` for screen columns
+    cursor_x: usize,
+    cursor_y: usize,
+    // Display column that moving up and down aims for, so passing through
+    // short lines doesn't lose it; `usize::MAX` sticks to the end of lines
+    preferred_col: usize,
+    // First buffer row and display column visible on screen
+    row_offset: usize,
+    col_offset: usize,
+    filename: Option<PathBuf>,
+    // Highlighting rules picked from the file extension
+    syntax: Option<Syntax>,
+    line_ending: LineEnding,
+    // Start the file with a UTF-8 byte order mark, as it did when opened
+    write_bom: bool,
+    dirty: bool,
+    // Saving is refused, e.g. when the file couldn't be decoded exactly
+    read_only: bool,
+    // Named positions set with `m`; the backtick mark is where the last jump
+    // started
+    marks: HashMap<char, (usize, usize)>,
+    // Positions jumps started from, oldest first, and where Ctrl-O and Ctrl-I
+    // have got to in them; the index is the list's length until Ctrl-O is used
+    jump_list: Vec<(usize, usize)>,
+    jump_index: usize,
+    // Closed folds, each from its header line up to (not including) the
+    // first line after it; they can nest. They're part of the view, so undo
+    // leaves them alone.
+    folds: Vec<Range<usize>>,
+    // Start of the search match `n`, `N` or a search last moved to
+    current_match: Option<(usize, usize)>,
+    // The last Visual selection, for `gv`
+    last_visual: Option<Selection>,
+    undo_stack: Vec<EditorSnapshot>,
+    redo_stack: Vec<EditorSnapshot>,
+    // Cursor position right after the last typed char, so that a run of
+    // typing is undone as one group
+    insert_group_end: Option<(usize, usize)>,
+}
+
+impl Buffer {
     fn new() -> Self {
+        Self::default()
+    }
+
+    // Load `path`, along with a warning worth showing about its contents
+    fn open(path: &Path) -> Result<(Self, Option<String>)> {
+        if path.is_dir() {
+            return Err(std::io::Error::other("is a directory"));
+        }
+        // A missing file is fine: it gets created on the first save
+        let (mut buf, warning) = match fs::read(path) {
+            Ok(bytes) => Self::from_bytes(bytes)?,
+            Err(e) if e.kind() == ErrorKind::NotFound => (Self::new(), None),
+            Err(e) => return Err(e),
+        };
+        buf.set_filename(path.to_path_buf());
+        Ok((buf, warning))
+    }
+
+    // An unnamed buffer holding `bytes`, along with a warning like `open`
+    fn from_bytes(bytes: Vec<u8>) -> Result<(Self, Option<String>)> {
+        let mut buf = Self::new();
+        // Text files don't contain NUL bytes; editing and saving anything
+        // else would corrupt it
+        if bytes.contains(&0) {
+            return Err(std::io::Error::other("file appears to be binary"));
+        }
+        // Invalid UTF-8 gets replacement chars, and saving those would mangle
+        // the original bytes
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(e) => {
+                buf.read_only = true;
+                String::from_utf8_lossy(e.as_bytes()).into_owned()
+            }
+        };
+
+        // The BOM isn't part of the text, but saving puts it back
+        let text = match text.strip_prefix('\u{feff}') {
+            Some(rest) => {
+                buf.write_bom = true;
+                rest.to_string()
+            }
+            None => text,
+        };
+
+        // Keep whichever line ending most lines use, so saving doesn't
+        // silently convert the file
+        let crlf = text.matches("\r\n").count();
+        let lf = text.matches('\n').count() - crlf;
+        if crlf > lf {
+            buf.line_ending = LineEnding::CrLf;
+        }
+        let warning = if buf.read_only {
+            Some("File is not valid UTF-8, opened read-only".to_string())
+        } else {
+            (crlf > 0 && lf > 0)
+                .then(|| format!("Mixed line endings, saving as {}", buf.line_ending.name()))
+        };
+
+        let text = text.strip_suffix('\n').unwrap_or(&text);
+        let lines: Vec<&str> = text
+            .split('\n')
+            .map(|line| line.strip_suffix('\r').unwrap_or(line))
+            .collect();
+        buf.content = TextBuffer::from_text(&lines.join("\n"));
+        Ok((buf, warning))
+    }
+
+    // The contents as they are written to disk
+    fn file_text(&self) -> String {
+        let ending = self.line_ending.as_str();
+        let mut text = if self.write_bom {
+            "\u{feff}".to_string()
+        } else {
+            String::new()
+        };
+        text.push_str(&self.content.to_string_with(ending));
+        text.push_str(ending);
+        text
+    }
+
+    // Move folds along with the lines edits have added and removed, opening
+    // the ones an edit went into
+    fn sync_folds(&mut self) {
+        for shift in self.content.take_shifts() {
+            match shift {
+                LineShift::Inserted { at, count } => self.folds.retain_mut(|fold| {
+                    if fold.start < at {
+                        return fold.end <= at;
+                    }
+                    *fold = fold.start + count..fold.end + count;
+                    true
+                }),
+                LineShift::Removed(lines) => self.folds.retain_mut(|fold| {
+                    if fold.start < lines.end {
+                        return fold.end <= lines.start;
+                    }
+                    *fold = fold.start - lines.len()..fold.end - lines.len();
+                    true
+                }),
+            }
+        }
+        // Undo can take lines away without saying how
+        let count = self.content.line_count();
+        self.folds.retain(|fold| fold.end <= count);
+    }
+
+    // Open the fold whose header is line `y`, if there is one
+    fn remove_fold(&mut self, y: usize) -> bool {
+        let count = self.folds.len();
+        self.folds.retain(|fold| fold.start != y);
+        self.folds.len() != count
+    }
+
+    // Open every fold hiding line `y`
+    fn open_folds_at(&mut self, y: usize) {
+        self.folds.retain(|fold| !(fold.start < y && y < fold.end));
+    }
+
+    // The fold shown as a summary on line `y`, unless it's hidden itself
+    fn fold_at(&self, y: usize) -> Option<Range<usize>> {
+        if self.hidden_by(y).is_some() {
+            return None;
+        }
+        self.folds
+            .iter()
+            .filter(|fold| fold.start == y)
+            .max_by_key(|fold| fold.end)
+            .cloned()
+    }
+
+    // The outermost fold hiding line `y`
+    fn hidden_by(&self, y: usize) -> Option<Range<usize>> {
+        self.folds
+            .iter()
+            .filter(|fold| fold.start < y && y < fold.end)
+            .min_by_key(|fold| fold.start)
+            .cloned()
+    }
+
+    // The nearest line after `y` that isn't hidden in a fold
+    fn next_visible(&self, y: usize) -> Option<usize> {
+        let mut next = y + 1;
+        while let Some(fold) = self.hidden_by(next) {
+            next = fold.end;
+        }
+        (next < self.content.line_count()).then_some(next)
+    }
+
+    // The nearest line before `y` that isn't hidden in a fold
+    fn prev_visible(&self, y: usize) -> Option<usize> {
+        let mut prev = y.checked_sub(1)?;
+        while let Some(fold) = self.hidden_by(prev) {
+            prev = fold.start;
+        }
+        Some(prev)
+    }
+
+    // `.name.swp` next to the file
+    fn swap_path(&self) -> Option<PathBuf> {
+        let path = self.filename.as_ref()?;
+        let file_name = path.file_name()?.to_string_lossy();
+        Some(path.with_file_name(format!(".{}.swp", file_name)))
+    }
+
+    // The swap file, if there is one newer than the file itself, which means
+    // the last session ended without saving
+    fn stale_swap(&self) -> Option<PathBuf> {
+        let swap = self.swap_path()?;
+        let swapped = fs::metadata(&swap).and_then(|m| m.modified()).ok()?;
+        let saved = self
+            .filename
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok());
+        match saved.and_then(|m| m.modified().ok()) {
+            Some(saved) if saved >= swapped => None,
+            _ => Some(swap),
+        }
+    }
+
+    fn remove_swap(&self) {
+        if let Some(swap) = self.swap_path() {
+            let _ = fs::remove_file(swap);
+        }
+    }
+
+    fn set_filename(&mut self, path: PathBuf) {
+        self.syntax = Syntax::from_path(&path);
+        self.filename = Some(path);
+    }
+
+    // Name shown on the status line
+    fn name(&self) -> String {
+        match &self.filename {
+            Some(path) => path.display().to_string(),
+            None => "[No Name]".to_string(),
+        }
+    }
+
+    // Just the last component of the name, for when the path is too long
+    fn short_name(&self) -> String {
+        match self.filename.as_ref().and_then(|path| path.file_name()) {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => self.name(),
+        }
+    }
+}
+
+impl Editor {
+    fn new(config: &Config) -> Self {
         Self {
-            content: vec![String::new()],
-            cursor_x: 0,
-            cursor_y: 0,
+            buf: Buffer::new(),
+            buffers: Vec::new(),
+            active: 0,
+            screen_cols: 80,
+            screen_rows: 24,
+            tab_width: config.tab_width,
+            expand_tabs: config.expand_tabs,
+            smarttab: config.smarttab,
+            auto_indent: config.auto_indent,
+            continue_lists: config.continue_lists,
+            show_line_numbers: config.show_line_numbers,
+            relative_numbers: false,
+            wrap: false,
+            list_mode: false,
+            cursorline: false,
+            colorcolumn: None,
+            scrolloff: 3,
             mode: Mode::Normal,
+            sel_x: 0,
+            sel_y: 0,
+            pending: None,
+            pending_block_insert: None,
+            replaced: Vec::new(),
+            completion: None,
+            keep_preferred_col: false,
+            pending_count: None,
+            operator_count: None,
+            showcmd: String::new(),
+            keymap: Keymap::new(&config.keys),
             quit: false,
+            theme: config.theme,
+            quit_pending: false,
+            prompt_buffer: String::new(),
+            command_buffer: String::new(),
+            command_history: Vec::new(),
+            history_index: None,
+            last_search: None,
+            last_find: None,
+            smartcase: false,
+            highlight_matches: false,
+            register: Register::Chars(String::new()),
+            use_clipboard: false,
+            clipboard: SystemClipboard::default(),
+            macros: HashMap::new(),
+            recording: None,
+            last_macro: None,
+            macro_depth: 0,
+            change_keys: Vec::new(),
+            change_repeatable: true,
+            change_started_at: 0,
+            last_change: Vec::new(),
+            edits: 0,
+            escape_sequence: {
+                let mut chars = config.escape_sequence.chars();
+                chars.next().zip(chars.next())
+            },
+            escape_timeout: Duration::from_millis(config.escape_timeout),
+            last_typed: None,
+            swap_interval: (config.swap_interval > 0)
+                .then(|| Duration::from_secs(config.swap_interval)),
+            swap_due: false,
+            last_input: Instant::now(),
+            recovery: None,
+            trim_trailing_whitespace: config.trim_trailing_whitespace,
+            date_format: config.date_format.clone(),
+            time_format: config.time_format.clone(),
+            cursor_shapes: config.cursor_shape,
+            cursor_shape: None,
+            status_message: None,
+            frame: Vec::new(),
         }
     }
 
+    // Edit text piped in on stdin, in an unnamed buffer. Keys then come from
+    // the terminal, which crossterm opens itself when stdin isn't one.
+    fn from_stdin(config: &Config) -> Result<Self> {
+        fs::File::open("/dev/tty")
+            .map_err(|_| std::io::Error::other("no terminal to read keys from"))?;
+        let mut bytes = Vec::new();
+        stdin().read_to_end(&mut bytes)?;
+        let mut editor = Self::new(config);
+        let (buf, warning) = Buffer::from_bytes(bytes)?;
+        editor.buf = buf;
+        if let Some(warning) = warning {
+            editor.set_status(warning);
+        }
+        Ok(editor)
+    }
+
+    fn open(path: &Path, config: &Config) -> Result<Self> {
+        let mut editor = Self::new(config);
+        let (buf, warning) = Buffer::open(path)?;
+        editor.buf = buf;
+        if let Some(warning) = warning {
+            editor.set_status(warning);
+        }
+        // The list is a convenience, so failing to update it isn't an error
+        let _ = recent::record(path);
+        if editor.swap_interval.is_some() {
+            editor.recovery = editor.buf.stale_swap();
+        }
+        Ok(editor)
+    }
+
     fn run(&mut self) -> Result<()> {
-        terminal::enable_raw_mode()?;
-        execute!(stdout(), terminal::EnterAlternateScreen)?;
+        let _terminal = TerminalGuard::enter(self.cursor_shapes)?;
+        let (cols, rows) = terminal::size()?;
+        self.resize(cols, rows);
 
+        let mut redraw = true;
         while !self.quit {
-            self.draw_screen()?;
-            self.process_keypress()?;
+            if redraw {
+                self.scroll();
+                self.draw_screen()?;
+            }
+            redraw = if event::poll(TICK)? {
+                self.process_event()?;
+                self.swap_due = true;
+                self.last_input = Instant::now();
+                true
+            } else {
+                self.tick()
+            };
+        }
+        self.buf.remove_swap();
+        for buf in &self.buffers {
+            buf.remove_swap();
         }
+        Ok(())
+    }
 
-        execute!(stdout(), terminal::LeaveAlternateScreen, cursor::Show)?;
-        terminal::disable_raw_mode()?;
+    // Put back the shape the terminal had before we changed it
+    fn restore_cursor_shape(&mut self) -> Result<()> {
+        if self.cursor_shape.take().is_some() {
+            queue!(stdout(), cursor::SetCursorStyle::DefaultUserShape)?;
+        }
         Ok(())
     }
 
-    fn draw_screen(&mut self) -> Result<()> {
-        execute!(
-            stdout(),
-            terminal::Clear(ClearType::All),
-            cursor::MoveTo(0, 0)
-        )?;
+    fn resize(&mut self, cols: u16, rows: u16) {
+        self.screen_cols = cols as usize;
+        self.screen_rows = rows as usize;
+        self.frame.clear();
+        self.scroll();
+    }
+
+    // Width of the gutter: the sign column while any line has a marker, and
+    // the line numbers
+    fn gutter_width(&self) -> usize {
+        self.sign_width() + self.number_width()
+    }
+
+    fn sign_width(&self) -> usize {
+        if self.buf.content.has_markers() {
+            2
+        } else {
+            0
+        }
+    }
+
+    // Width of the line number column, including its trailing space
+    fn number_width(&self) -> usize {
+        if self.show_line_numbers || self.relative_numbers {
+            self.buf.content.line_count().to_string().len() + 1
+        } else {
+            0
+        }
+    }
+
+    // Text area size, leaving room for the gutter and the status line
+    fn text_area(&self) -> (usize, usize) {
+        (
+            self.screen_cols.saturating_sub(self.gutter_width()).max(1),
+            self.screen_rows.saturating_sub(1).max(1),
+        )
+    }
+
+    fn scroll(&mut self) {
+        let (width, height) = self.text_area();
+        self.buf.sync_folds();
 
-        // Draw content
-        for (i, line) in self.content.iter().enumerate() {
-            if i > 0 {
-                print!("\r\n");
+        // Jumps, searches and undo can land inside a fold, which opens it
+        self.buf.open_folds_at(self.buf.cursor_y);
+        while let Some(fold) = self.buf.hidden_by(self.buf.row_offset) {
+            self.buf.row_offset = fold.start;
+        }
+
+        // The margin shrinks near the ends of the file, where there are no
+        // more lines to show
+        let margin = self.scroll_margin();
+        let top = self.step_lines(self.buf.cursor_y, margin, false);
+        let bottom = self.step_lines(self.buf.cursor_y, margin, true);
+        if top < self.buf.row_offset {
+            self.buf.row_offset = top;
+        }
+        if self.wrap {
+            // Nothing is cut off to the side, but the lines around the cursor
+            // may take several rows each
+            self.buf.col_offset = 0;
+            let rows_below: usize = (self.buf.cursor_y + 1..=bottom)
+                .map(|y| self.rows_of_line(y, width))
+                .sum();
+            while self.buf.row_offset < top && self.wrapped_cursor().0 + rows_below >= height {
+                self.buf.row_offset = self.step_lines(self.buf.row_offset, 1, true);
             }
-            print!("{}", line);
+            return;
+        }
+        let first = self.step_lines(bottom, height.saturating_sub(1), false);
+        if first > self.buf.row_offset {
+            self.buf.row_offset = first;
         }
 
-        // Draw status line
-        let mode_str = match self.mode {
-            Mode::Normal => "NORMAL",
-            Mode::Insert => "INSERT",
+        let line = self.buf.content.line(self.buf.cursor_y);
+        let col = display_col(&line, self.buf.cursor_x, self.tab_width);
+        // Keep the cursor off the `<` and `>` columns marking more text
+        if col < self.buf.col_offset || (col == self.buf.col_offset && col > 0) {
+            self.buf.col_offset = col.saturating_sub(1);
+        }
+        let line_width = display_col(&line, char_count(&line), self.tab_width);
+        let room = if line_width > col + 1 && width > 2 {
+            width - 1
+        } else {
+            width
         };
-        let status = format!(
-            "\r\n-- {} -- Cursor: ({}, {})",
-            mode_str, self.cursor_x, self.cursor_y
-        );
-        print!("{}", status);
+        if col >= self.buf.col_offset + room {
+            self.buf.col_offset = col + 1 - room;
+        }
+    }
+
+    // Move the view and the cursor together by `lines` lines, keeping the
+    // cursor's column. The view stops once the last line is at the bottom,
+    // after which only the cursor moves.
+    fn scroll_view(&mut self, lines: usize, forward: bool) {
+        let y = self.step_lines(self.buf.cursor_y, lines, forward);
+        let mut offset = self.step_lines(self.buf.row_offset, lines, forward);
+        if forward {
+            let last = self.buf.content.line_count() - 1;
+            let max_offset = self.step_lines(last, self.text_area().1 - 1, false);
+            offset = offset.min(max_offset.max(self.buf.row_offset));
+        }
+        self.buf.row_offset = offset;
+        self.move_to_line(y);
+    }
+
+    // The line `count` lines on from `y`, counting a closed fold as one
+    // line and stopping at either end of the buffer
+    fn step_lines(&self, mut y: usize, count: usize, forward: bool) -> usize {
+        for _ in 0..count {
+            let next = if forward {
+                self.buf.next_visible(y)
+            } else {
+                self.buf.prev_visible(y)
+            };
+            match next {
+                Some(next) => y = next,
+                None => break,
+            }
+        }
+        y
+    }
+
+    // Screen rows line `y` takes: none inside a closed fold, and just the one
+    // for the summary of a fold or when not wrapping
+    fn rows_of_line(&self, y: usize, width: usize) -> usize {
+        if self.buf.hidden_by(y).is_some() {
+            0
+        } else if !self.wrap || self.buf.fold_at(y).is_some() {
+            1
+        } else {
+            wrap_starts(&self.buf.content.line(y), width, self.tab_width).len()
+        }
+    }
+
+    // `scrolloff`, limited so that both margins fit on screen
+    fn scroll_margin(&self) -> usize {
+        self.scrolloff.min(self.text_area().1.saturating_sub(1) / 2)
+    }
+
+    // Vim-style ruler: cursor line out of the total, then where the view sits
+    fn ruler(&self) -> String {
+        let total = self.buf.content.line_count();
+        let height = self.text_area().1;
+        let position = if total <= height {
+            "All".to_string()
+        } else if self.buf.row_offset == 0 {
+            "Top".to_string()
+        } else if self.buf.row_offset + height >= total {
+            "Bot".to_string()
+        } else {
+            format!("{}%", (self.buf.cursor_y + 1) * 100 / total)
+        };
+        format!("{}/{}  {:>3}", self.buf.cursor_y + 1, total, position)
+    }
+
+    fn draw_screen(&mut self) -> Result<()> {
+        let (width, height) = self.text_area();
+        queue!(stdout(), cursor::Hide)?;
+        if self.frame.len() != height {
+            queue!(stdout(), terminal::Clear(ClearType::All))?;
+            self.frame = vec![Vec::new(); height];
+        }
+
+        // Draw the visible slice of content, skipping rows that look the same
+        // as last frame
+        let (signs, numbers) = (self.sign_width(), self.number_width());
+        let gutter = signs + numbers;
+        let rows = if self.wrap {
+            self.wrapped_rows(height)
+        } else {
+            let cols = self.buf.col_offset..self.buf.col_offset + width;
+            let mut visible = vec![(self.buf.row_offset, cols.clone())];
+            while visible.len() < height {
+                match self.buf.next_visible(visible[visible.len() - 1].0) {
+                    Some(y) => visible.push((y, cols.clone())),
+                    None => break,
+                }
+            }
+            visible
+        };
+        // Show the partner of a bracket under the cursor, if it's on screen
+        let bracket = match self.mode {
+            Mode::Normal | Mode::Insert | Mode::Replace => {
+                let last = rows.last().map_or(0, |(y, _)| *y);
+                self.match_bracket_within(self.buf.row_offset..last + 1)
+            }
+            _ => None,
+        };
+        let mut frame = vec![Vec::new(); height];
+        for (row, (y, cols)) in rows.into_iter().enumerate() {
+            let out = &mut frame[row];
+            // Only the first row of a wrapped line gets a marker and number
+            let first_row = cols.start == 0 || !self.wrap;
+            if signs > 0 {
+                match self.buf.content.marker(y).filter(|_| first_row) {
+                    Some(marker) => queue!(
+                        out,
+                        SetForegroundColor(marker.color),
+                        Print(marker.symbol),
+                        SetAttribute(Attribute::Reset),
+                        Print(' ')
+                    )?,
+                    None => write!(out, "{}", " ".repeat(signs))?,
+                }
+            }
+            if numbers > 0 && !first_row {
+                write!(out, "{}", " ".repeat(numbers))?;
+            } else if numbers > 0 {
+                let number = if self.relative_numbers && y != self.buf.cursor_y {
+                    y.abs_diff(self.buf.cursor_y)
+                } else {
+                    y + 1
+                };
+                write!(out, "{:>w$} ", number, w = numbers - 1)?;
+            }
+            if let Some(fold) = self.buf.fold_at(y) {
+                self.draw_fold(out, y, fold.len())?;
+                continue;
+            }
+            let bracket = bracket.filter(|b| b.1 == y).map(|b| b.0);
+            self.draw_line(out, y, cols, bracket)?;
+        }
+        for (row, line) in frame.iter().enumerate() {
+            if *line != self.frame[row] {
+                queue!(
+                    stdout(),
+                    cursor::MoveTo(0, row as u16),
+                    terminal::Clear(ClearType::CurrentLine)
+                )?;
+                stdout().write_all(line)?;
+            }
+        }
+        self.frame = frame;
 
-        // Move cursor to current position
-        execute!(
+        // Draw status line on the last row
+        let message = self.status_message.as_ref().map_or("", |(m, _)| m.as_str());
+        let status = match self.mode {
+            _ if self.recovery.is_some() => {
+                "Swap file found: r to recover it, d to delete it, any other key to ignore"
+                    .to_string()
+            }
+            Mode::SaveAs => format!("Save as: {}", self.prompt_buffer),
+            Mode::Command => format!(":{}", self.command_buffer),
+            Mode::Search => format!("/{}", self.command_buffer),
+            mode => {
+                let mode_str = match mode {
+                    Mode::Insert => "INSERT",
+                    Mode::Replace => "REPLACE",
+                    Mode::Visual => "VISUAL",
+                    Mode::VisualLine => "VISUAL LINE",
+                    Mode::VisualBlock => "VISUAL BLOCK",
+                    _ => "NORMAL",
+                };
+                let recording = match &self.recording {
+                    Some((register, _)) => format!("recording @{} ", register),
+                    None => String::new(),
+                };
+                let mut flags = String::new();
+                if self.buf.dirty {
+                    flags.push_str(" [+]");
+                }
+                if self.buf.read_only {
+                    flags.push_str(" [RO]");
+                }
+                let ruler = match self.showcmd.as_str() {
+                    "" => self.ruler(),
+                    keys => format!("{}  {}", keys, self.ruler()),
+                };
+                let room = self.screen_cols.saturating_sub(ruler.width() + 1);
+                // Fall back to just the file's base name when the path
+                // doesn't fit
+                let info = |name: String| {
+                    format!(
+                        "-- {} -- {}[{}/{}] {}{}",
+                        mode_str,
+                        recording,
+                        self.active + 1,
+                        self.buffer_count(),
+                        name,
+                        flags
+                    )
+                };
+                let mut left = info(self.buf.name());
+                if left.width() > room {
+                    left = info(self.buf.short_name());
+                }
+                let left = format!("{} {}", left, message);
+                format!("{} {}", fit_width(&left, room), ruler)
+            }
+        };
+        let status_row = self.screen_rows.saturating_sub(1);
+        queue!(
             stdout(),
-            cursor::MoveTo(self.cursor_x as u16, self.cursor_y as u16),
-            cursor::Show
+            cursor::MoveTo(0, status_row as u16),
+            Print(fit_width(&status, self.screen_cols))
         )?;
 
+        // Move cursor to current position, or onto the prompt while typing one
+        let (x, y) = match self.mode {
+            Mode::SaveAs | Mode::Command | Mode::Search => (
+                status.width().min(self.screen_cols.saturating_sub(1)),
+                status_row,
+            ),
+            _ if self.wrap => {
+                let (row, col) = self.wrapped_cursor();
+                (gutter + col, row.min(height - 1))
+            }
+            _ => {
+                let row = (self.buf.row_offset..self.buf.cursor_y)
+                    .map(|y| self.rows_of_line(y, width))
+                    .sum();
+                // A closed fold's summary isn't scrolled sideways
+                let col = match self.buf.fold_at(self.buf.cursor_y) {
+                    Some(_) => 0,
+                    None => {
+                        display_col(
+                            &self.buf.content.line(self.buf.cursor_y),
+                            self.buf.cursor_x,
+                            self.tab_width,
+                        ) - self.buf.col_offset
+                    }
+                };
+                (gutter + col, row)
+            }
+        };
+        let shape = CursorShape::for_mode(self.mode);
+        if self.cursor_shapes && self.cursor_shape != Some(shape) {
+            queue!(stdout(), shape.style())?;
+            self.cursor_shape = Some(shape);
+        }
+        execute!(stdout(), cursor::MoveTo(x as u16, y as u16), cursor::Show)?;
+
         stdout().flush()?;
         Ok(())
     }
 
-    fn process_keypress(&mut self) -> Result<()> {
-        if let Event::Key(key) = event::read()? {
-            match self.mode {
-                Mode::Normal => self.handle_normal_mode(key),
-                Mode::Insert => self.handle_insert_mode(key),
-            }?;
+    // Draw the display columns `cols` of line `y`, marking the char at index
+    // `bracket`; tabs and wide chars cut by either edge show as blanks
+    fn draw_line(
+        &self,
+        out: &mut impl Write,
+        y: usize,
+        cols: Range<usize>,
+        bracket: Option<usize>,
+    ) -> Result<()> {
+        let line = &self.buf.content.line(y);
+        let selected = self.selection_on_line(y);
+        let kinds = self
+            .buf
+            .syntax
+            .filter(|_| self.theme != Theme::Mono)
+            .map(|syntax| highlight_line(line, syntax));
+        let matched = self.search_matches(line);
+        // Chars from here on are trailing whitespace
+        let trailing = char_count(line.trim_end());
+        let line_bg = (self.cursorline && y == self.buf.cursor_y).then_some(CURSORLINE_BG);
+        let ruler = self.colorcolumn.map(|col| col - 1);
+        let (mut start, mut end) = (cols.start, cols.end);
+        let width = self.text_area().0;
+        let mut current = CellStyle::default();
+        let mut col = 0;
+        let mut used = 0;
+        let mut clipped = false;
+
+        // Without wrapping, `<` and `>` in the first and last columns show
+        // there's more of the line scrolled off to that side
+        let marker = CellStyle {
+            bg: line_bg,
+            dim: true,
+            ..CellStyle::default()
+        };
+        let line_width = display_col(line, char_count(line), self.tab_width);
+        let more_right = !self.wrap && line_width > end && end - start > 1;
+        if more_right {
+            end -= 1;
+        }
+        if !self.wrap && start > 0 && line_width > 0 && end > start {
+            set_style(out, marker)?;
+            current = marker;
+            queue!(out, Print('<'))?;
+            used += 1;
+            start += 1;
+        }
+
+        for (i, c) in line.chars().enumerate() {
+            let w = char_width(c, col, self.tab_width);
+            if col + w <= start {
+                col += w;
+                continue;
+            }
+            let style = if let Some(m) = matched.iter().find(|m| m.contains(&i)) {
+                let bg = if self.buf.current_match == Some((m.start, y)) {
+                    CURRENT_MATCH_BG
+                } else {
+                    Color::Yellow
+                };
+                CellStyle {
+                    fg: Some(Color::Black),
+                    bg: Some(bg),
+                    reverse: selected.contains(&i),
+                    dim: false,
+                }
+            } else {
+                CellStyle {
+                    fg: kinds.as_ref().and_then(|kinds| kinds[i].color()),
+                    // The selection shows through the cursor line
+                    bg: if bracket == Some(i) {
+                        Some(Color::DarkCyan)
+                    } else {
+                        line_bg.filter(|_| !selected.contains(&i))
+                    },
+                    reverse: selected.contains(&i),
+                    dim: false,
+                }
+            };
+            // The ruler shows through the cursor line but not the selection
+            // or a match
+            let on_ruler = ruler.is_some_and(|r| col <= r && r < col + w && r >= start && r < end);
+            let covered = style.reverse || style.bg.is_some_and(|bg| Some(bg) != line_bg);
+            let bg = if on_ruler && !covered {
+                Some(COLORCOLUMN_BG)
+            } else {
+                style.bg
+            };
+            let glyph = self.list_glyph(c, i >= trailing);
+            let style = CellStyle {
+                bg,
+                dim: glyph.is_some(),
+                ..style
+            };
+            if style != current {
+                set_style(out, style)?;
+                current = style;
+            }
+
+            let visible = (col + w).min(end) - col.max(start);
+            if let (Some(glyph), true) = (glyph, col >= start) {
+                // The glyph takes the first column of the char, and a tab is
+                // padded out to its full width
+                queue!(out, Print(glyph), Print(" ".repeat(visible - 1)))?;
+            } else if c == '\t' || col < start || col + w > end {
+                queue!(out, Print(" ".repeat(visible)))?;
+            } else {
+                queue!(out, Print(c))?;
+            }
+            used += visible;
+            col += w;
+            if col >= end {
+                clipped = true;
+                break;
+            }
+        }
+        if more_right {
+            set_style(out, marker)?;
+            current = marker;
+            queue!(out, Print('>'))?;
+            used += 1;
+        }
+
+        // A selection running past the end of the line includes its newline;
+        // a line-wise one covers the whole row
+        if selected.contains(&char_count(line)) {
+            let pad = if self.mode == Mode::VisualLine {
+                width.saturating_sub(used)
+            } else if !clipped && col >= start {
+                1
+            } else {
+                0
+            };
+            current = CellStyle {
+                reverse: true,
+                ..CellStyle::default()
+            };
+            set_style(out, current)?;
+            queue!(out, Print(" ".repeat(pad)))?;
+            used += pad;
+        }
+        // Past the end of a shorter line the ruler is a tinted blank
+        let ruler_x = ruler
+            .filter(|r| (cols.start..cols.end).contains(r))
+            .map(|r| r - cols.start)
+            .filter(|&x| x >= used && x < width);
+        if let Some(x) = ruler_x {
+            current = CellStyle {
+                bg: line_bg,
+                ..CellStyle::default()
+            };
+            set_style(out, current)?;
+            queue!(out, Print(" ".repeat(x - used)))?;
+            current = CellStyle {
+                bg: Some(COLORCOLUMN_BG),
+                ..CellStyle::default()
+            };
+            set_style(out, current)?;
+            queue!(out, Print(' '))?;
+            used = x + 1;
+        }
+        if let Some(bg) = line_bg {
+            current = CellStyle {
+                bg: Some(bg),
+                ..CellStyle::default()
+            };
+            set_style(out, current)?;
+            queue!(out, Print(" ".repeat(width.saturating_sub(used))))?;
+        }
+        if current != CellStyle::default() {
+            set_style(out, CellStyle::default())?;
         }
         Ok(())
     }
 
-    fn handle_normal_mode(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Char('q') => self.quit = true,
-            KeyCode::Char('i') => self.mode = Mode::Insert,
-            KeyCode::Char('h') => self.move_cursor_left(),
-            KeyCode::Char('j') => self.move_cursor_down(),
-            KeyCode::Char('k') => self.move_cursor_up(),
-            KeyCode::Char('l') => self.move_cursor_right(),
-            _ => (),
+    // The row standing in for a closed fold of `count` lines starting at `y`
+    fn draw_fold(&self, out: &mut impl Write, y: usize, count: usize) -> Result<()> {
+        let width = self.text_area().0;
+        let header = self.buf.content.line(y).replace('\t', " ");
+        let summary = format!("+--{:>3} lines: {} ", count, header.trim());
+        let dashes = "-".repeat(width.saturating_sub(summary.width()));
+        let summary = fit_width(&(summary + &dashes), width);
+        let bg = (self.cursorline && y == self.buf.cursor_y).then_some(CURSORLINE_BG);
+        queue!(out, SetForegroundColor(Color::DarkGrey))?;
+        if let Some(bg) = bg {
+            queue!(out, SetBackgroundColor(bg))?;
         }
+        queue!(out, Print(summary), SetAttribute(Attribute::Reset))?;
         Ok(())
     }
 
-    fn handle_insert_mode(&mut self, key: KeyEvent) -> Result<()> {
-        match key.code {
-            KeyCode::Esc => self.mode = Mode::Normal,
-            KeyCode::Char(c) => self.insert_char(c),
-            KeyCode::Backspace => self.delete_char(),
-            KeyCode::Enter => self.insert_newline(),
-            _ => (),
+    // What to draw in place of `c` in list mode, if anything
+    fn list_glyph(&self, c: char, trailing: bool) -> Option<char> {
+        match c {
+            _ if !self.list_mode => None,
+            '\t' => Some('→'),
+            ' ' if trailing => Some('·'),
+            _ => None,
         }
-        Ok(())
     }
 
-    fn move_cursor_left(&mut self) {
-        if self.cursor_x > 0 {
-            self.cursor_x -= 1;
+    // Line and display columns shown on each screen row when wrapping, from
+    // the top of the view down to at most `height` rows
+    fn wrapped_rows(&self, height: usize) -> Vec<(usize, Range<usize>)> {
+        let width = self.text_area().0;
+        let mut rows = Vec::new();
+        let mut next = Some(self.buf.row_offset);
+        while let Some(y) = next {
+            next = self.buf.next_visible(y);
+            if self.buf.fold_at(y).is_some() {
+                rows.push((y, 0..width));
+                if rows.len() == height {
+                    return rows;
+                }
+                continue;
+            }
+            let line = &self.buf.content.line(y);
+            let starts = wrap_starts(line, width, self.tab_width);
+            let line_width = display_col(line, char_count(line), self.tab_width);
+            for (i, &(_, col)) in starts.iter().enumerate() {
+                let end = starts
+                    .get(i + 1)
+                    .map_or(line_width.max(col + width), |s| s.1);
+                rows.push((y, col..end));
+                if rows.len() == height {
+                    return rows;
+                }
+            }
         }
+        rows
     }
 
-    fn move_cursor_right(&mut self) {
-        let line_len = self.content[self.cursor_y].width();
-        if self.cursor_x < line_len {
-            self.cursor_x += 1;
+    // Screen row and column of the cursor when wrapping, counted from the
+    // top of the view
+    fn wrapped_cursor(&self) -> (usize, usize) {
+        let width = self.text_area().0;
+        let rows_above: usize = (self.buf.row_offset..self.buf.cursor_y)
+            .map(|y| self.rows_of_line(y, width))
+            .sum();
+        if self.buf.fold_at(self.buf.cursor_y).is_some() {
+            return (rows_above, 0);
+        }
+        let line = &self.buf.content.line(self.buf.cursor_y);
+        let starts = wrap_starts(line, width, self.tab_width);
+        let row = starts
+            .iter()
+            .rposition(|&(i, _)| i <= self.buf.cursor_x)
+            .unwrap_or(0);
+        let col = display_col(line, self.buf.cursor_x, self.tab_width) - starts[row].1;
+        // In Insert mode the cursor can sit just past a full row
+        if col >= width {
+            (rows_above + row + 1, 0)
+        } else {
+            (rows_above + row, col)
         }
     }
 
-    fn move_cursor_up(&mut self) {
-        if self.cursor_y > 0 {
-            self.cursor_y -= 1;
-            let line_len = self.content[self.cursor_y].width();
-            if self.cursor_x > line_len {
-                self.cursor_x = line_len;
-            }
+    // Char ranges of the search matches to highlight on `line`
+    fn search_matches(&self, line: &str) -> Vec<Range<usize>> {
+        let pattern = match &self.last_search {
+            Some(pattern) if self.highlight_matches => pattern,
+            _ => return Vec::new(),
+        };
+        let len = char_count(whole_word(pattern).unwrap_or(pattern));
+        find_all(line, pattern, self.ignore_case(pattern))
+            .into_iter()
+            .map(|at| {
+                let start = char_count(&line[..at]);
+                start..start + len
+            })
+            .collect()
+    }
+
+    // Ends of the Visual selection in buffer order, both inclusive
+    fn selection(&self) -> ((usize, usize), (usize, usize)) {
+        let anchor = (self.sel_x, self.sel_y);
+        let cursor = (self.buf.cursor_x, self.buf.cursor_y);
+        if (anchor.1, anchor.0) <= (cursor.1, cursor.0) {
+            (anchor, cursor)
+        } else {
+            (cursor, anchor)
         }
     }
 
-    fn move_cursor_down(&mut self) {
-        if self.cursor_y < self.content.len() - 1 {
-            self.cursor_y += 1;
-            let line_len = self.content[self.cursor_y].width();
-            if self.cursor_x > line_len {
-                self.cursor_x = line_len;
+    // Char indices of line `y` inside the selection; the line length stands
+    // for its newline
+    fn selection_on_line(&self, y: usize) -> Range<usize> {
+        if !self.mode.is_visual() {
+            return 0..0;
+        }
+        let ((start_x, start_y), (end_x, end_y)) = self.selection();
+        if y < start_y || y > end_y {
+            return 0..0;
+        }
+        if self.mode == Mode::VisualLine {
+            return 0..usize::MAX;
+        }
+        if self.mode == Mode::VisualBlock {
+            return chars_in_cols(&self.buf.content.line(y), self.block_cols(), self.tab_width);
+        }
+        let from = if y == start_y { start_x } else { 0 };
+        let to = if y == end_y {
+            end_x + 1
+        } else {
+            self.buf.content.line_len(y) + 1
+        };
+        from..to
+    }
+
+    fn process_event(&mut self) -> Result<()> {
+        let before = (self.buf.cursor_x, self.buf.cursor_y);
+        self.keep_preferred_col = false;
+        match event::read()? {
+            Event::Key(key) => {
+                // Keys that start or stop the recording aren't part of it
+                let was_recording = self.recording.is_some();
+                if self.is_idle() {
+                    self.change_keys.clear();
+                    self.change_repeatable = true;
+                    self.change_started_at = self.edits;
+                }
+                self.handle_key(key)?;
+                if let Some((_, keys)) = self.recording.as_mut().filter(|_| was_recording) {
+                    keys.push(key);
+                }
+                self.note_change_key(key);
+                self.note_showcmd(key);
             }
+            Event::Mouse(mouse) => self.handle_mouse(mouse),
+            Event::Paste(text) => self.handle_paste(&text),
+            Event::Resize(cols, rows) => self.resize(cols, rows),
+            _ => (),
         }
+        // Edits and jumps may leave the cursor past the last char
+        if self.mode == Mode::Normal {
+            self.clamp_cursor_x();
+        }
+        if !self.keep_preferred_col && (self.buf.cursor_x, self.buf.cursor_y) != before {
+            self.buf.preferred_col = display_col(
+                &self.buf.content.line(self.buf.cursor_y),
+                self.buf.cursor_x,
+                self.tab_width,
+            );
+        }
+        Ok(())
     }
 
-    fn insert_char(&mut self, c: char) {
-        let line = &mut self.content[self.cursor_y];
-        line.insert(self.cursor_x, c);
-        self.cursor_x += 1;
+    // Normal mode with no command half typed
+    fn is_idle(&self) -> bool {
+        self.mode == Mode::Normal && self.pending.is_none() && self.pending_count.is_none()
     }
 
-    fn delete_char(&mut self) {
-        let line = &mut self.content[self.cursor_y];
-        if self.cursor_x > 0 {
-            line.remove(self.cursor_x - 1);
-            self.cursor_x -= 1;
-        } else if self.cursor_y > 0 {
-            let current_line = self.content.remove(self.cursor_y);
-            self.cursor_y -= 1;
-            self.cursor_x = self.content[self.cursor_y].len();
-            self.content[self.cursor_y].push_str(&current_line);
+    // Add `key` to the command being typed, keeping the command for `.` once
+    // it is done if it changed the buffer. Commands going through the command
+    // line or Visual mode aren't repeated, and neither is `.` itself.
+    fn note_change_key(&mut self, key: KeyEvent) {
+        self.change_keys.push(key);
+        if !matches!(self.mode, Mode::Normal | Mode::Insert | Mode::Replace) {
+            self.change_repeatable = false;
+        }
+        if self.is_idle()
+            && self.change_repeatable
+            && self.edits != self.change_started_at
+            && self.change_keys[0].code != KeyCode::Char('.')
+        {
+            self.last_change = std::mem::take(&mut self.change_keys);
         }
     }
 
-    fn insert_newline(&mut self) {
-        let current_line = &mut self.content[self.cursor_y];
-        let new_line = current_line.split_off(self.cursor_x);
-        self.content.insert(self.cursor_y + 1, new_line);
-        self.cursor_y += 1;
-        self.cursor_x = 0;
+    // Keep `key` on show while the command it's part of waits for more
+    fn note_showcmd(&mut self, key: KeyEvent) {
+        if self.pending.is_none() && self.pending_count.is_none() {
+            self.showcmd.clear();
+            return;
+        }
+        match key.code {
+            KeyCode::Char(c) if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.showcmd.push('^');
+                self.showcmd.push(c.to_ascii_uppercase());
+            }
+            KeyCode::Char(c) => self.showcmd.push(c),
+            _ => (),
+        }
     }
-}
 
-fn main() -> Result<()> {
-    let mut editor = Editor::new();
-    editor.run()
+    // `.`: replay the keys of the last change `count` times
+    fn repeat_change(&mut self, count: usize) -> Result<()> {
+        let keys = self.last_change.clone();
+        for _ in 0..count {
+            for &key in &keys {
+                self.handle_key(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Result<()> {
+        if let Some(swap) = self.recovery.take() {
+            self.handle_recovery(key, &swap);
+            return Ok(());
+        }
+        self.dispatch_key(key)
+    }
+
+    fn dispatch_key(&mut self, key: KeyEvent) -> Result<()> {
+        // Keys replayed by macros and `.` come without a redraw in between
+        self.buf.sync_folds();
+        match self.mode {
+            Mode::Normal => self.handle_normal_mode(key),
+            Mode::Insert => self.handle_insert_mode(key),
+            Mode::Replace => self.handle_replace_mode(key),
+            Mode::SaveAs => self.handle_save_as_mode(key),
+            Mode::Command | Mode::Search => self.handle_command_mode(key),
+            Mode::Visual | Mode::VisualLine | Mode::VisualBlock => {
+                let selection = self.current_selection();
+                let result = self.handle_visual_mode(key);
+                if !self.mode.is_visual() {
+                    self.buf.last_visual = Some(selection);
+                }
+                result
+            }
+        }
+    }
+
+    // Answer to the swap file prompt shown on startup
+    fn handle_recovery(&mut self, key: KeyEvent, swap: &Path) {
+        match key.code {
+            KeyCode::Char('r') => match fs::read_to_string(swap) {
+                Ok(text) => {
+                    if !self.record_undo() {
+                        return;
+                    }
+                    let text = text.strip_prefix('\u{feff}').unwrap_or(&text);
+                    let text = text.strip_suffix('\n').unwrap_or(text);
+                    self.buf.content = TextBuffer::from_text(&text.replace("\r\n", "\n"));
+                    self.clamp_cursor();
+                    self.buf.dirty = true;
+                    self.set_status("Recovered from swap file; :w to keep the changes");
+                }
+                Err(e) => self.set_status(format!("Error reading swap file: {}", e)),
+            },
+            KeyCode::Char('d') => {
+                if let Err(e) = fs::remove_file(swap) {
+                    self.set_status(format!("Error deleting swap file: {}", e));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    // Timed work done while there is no input; returns whether the screen
+    // needs redrawing
+    fn tick(&mut self) -> bool {
+        let mut redraw = false;
+        if let Some(interval) = self.swap_interval {
+            if self.swap_due && self.last_input.elapsed() >= interval {
+                self.swap_due = false;
+                // Only a failed write has anything to show
+                self.write_swap();
+                redraw = true;
+            }
+        }
+        if let Some((_, set_at)) = &self.status_message {
+            if set_at.elapsed() >= STATUS_TIMEOUT {
+                self.status_message = None;
+                redraw = true;
+            }
+        }
+        redraw
+    }
+
+    // Write the current buffer's unsaved changes next to it, so they survive
+    // a crash
+    fn write_swap(&mut self) {
+        if !self.buf.dirty {
+            return;
+        }
+        let Some(path) = self.buf.swap_path() else {
+            return;
+        };
+        if let Err(e) = fs::write(&path, self.buf.file_text()) {
+            self.set_status(format!("Error writing swap file: {}", e));
+        }
+    }
+
+    fn handle_mouse(&mut self, mouse: MouseEvent) {
+        if matches!(self.mode, Mode::SaveAs | Mode::Command | Mode::Search) {
+            return;
+        }
+        let (_, height) = self.text_area();
+        match mouse.kind {
+            MouseEventKind::Down(MouseButton::Left) => {
+                let row = mouse.row as usize;
+                if row >= height {
+                    return;
+                }
+                let col = (mouse.column as usize).saturating_sub(self.gutter_width());
+                if self.wrap {
+                    let rows = self.wrapped_rows(height);
+                    let Some((y, cols)) = rows.get(row).or(rows.last()).cloned() else {
+                        return;
+                    };
+                    self.buf.cursor_y = y;
+                    // Stay on this row even past a break made at a word boundary
+                    let col = (cols.start + col).min(cols.end.saturating_sub(1).max(cols.start));
+                    self.buf.cursor_x = char_at_col(&self.buf.content.line(y), col, self.tab_width);
+                    return;
+                }
+                // Each closed fold takes a single row
+                self.buf.cursor_y = self.step_lines(self.buf.row_offset, row, true);
+                self.buf.cursor_x = char_at_col(
+                    &self.buf.content.line(self.buf.cursor_y),
+                    self.buf.col_offset + col,
+                    self.tab_width,
+                );
+            }
+            // The wheel moves the view, dragging the cursor along only when it
+            // would otherwise leave the screen or its scrolloff margin
+            MouseEventKind::ScrollDown => {
+                self.buf.row_offset = self.step_lines(self.buf.row_offset, SCROLL_LINES, true);
+                let first = self.step_lines(self.buf.row_offset, self.scroll_margin(), true);
+                if self.buf.cursor_y < first {
+                    self.move_to_line(first);
+                }
+            }
+            MouseEventKind::ScrollUp => {
+                self.buf.row_offset = self.step_lines(self.buf.row_offset, SCROLL_LINES, false);
+                let rows = (height - 1).saturating_sub(self.scroll_margin());
+                let last = self.step_lines(self.buf.row_offset, rows, true);
+                if self.buf.cursor_y > last {
+                    self.move_to_line(last);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_normal_mode(&mut self, key: KeyEvent) -> Result<()> {
+        // A pending quit only survives until the next key
+        let quit_pending = std::mem::take(&mut self.quit_pending);
+
+        if self.accumulate_count(key) {
+            return Ok(());
+        }
+
+        let count = self.take_count();
+        if let Some(pending) = self.pending.take() {
+            self.handle_pending(pending, key, count);
+            return Ok(());
+        }
+
+        let n = count.unwrap_or(1);
+        let Some(action) = self.keymap.get(Mode::Normal, key) else {
+            return Ok(());
+        };
+        match action {
+            Action::Save => self.save_or_report(),
+            Action::Redo => self.redo(),
+            Action::JumpBack => self.walk_jumps(n, true),
+            Action::JumpForward => self.walk_jumps(n, false),
+            Action::ShowStats => self.show_stats(),
+            Action::Undo => self.undo(),
+            Action::Quit => {
+                let dirty = self.buf.dirty || self.buffers.iter().any(|b| b.dirty);
+                if dirty && !quit_pending {
+                    self.quit_pending = true;
+                    self.set_status("Unsaved changes! Press Ctrl-Q again to quit");
+                } else {
+                    self.quit = true;
+                }
+            }
+            Action::Record => match self.recording.take() {
+                Some((register, keys)) => {
+                    self.macros.insert(register, keys);
+                }
+                None => self.pending = Some(Pending::Record),
+            },
+            Action::PlayMacro => {
+                self.pending = Some(Pending::Play);
+                self.pending_count = count;
+            }
+            Action::SetMark => self.pending = Some(Pending::SetMark),
+            Action::Insert => self.mode = Mode::Insert,
+            Action::Append => {
+                let line_len = self.buf.content.line_len(self.buf.cursor_y);
+                self.buf.cursor_x = (self.buf.cursor_x + 1).min(line_len);
+                self.mode = Mode::Insert;
+            }
+            Action::AppendAtEnd => {
+                self.buf.cursor_x = self.buf.content.line_len(self.buf.cursor_y);
+                self.mode = Mode::Insert;
+            }
+            Action::OpenBelow => self.open_line(self.buf.cursor_y + 1),
+            Action::OpenAbove => self.open_line(self.buf.cursor_y),
+            Action::CommandLine => {
+                self.command_buffer.clear();
+                self.mode = Mode::Command;
+            }
+            Action::Search => {
+                self.command_buffer.clear();
+                self.mode = Mode::Search;
+            }
+            Action::SearchNext => self.search_next(true),
+            Action::SearchPrevious => self.search_next(false),
+            Action::SearchWordForward => self.search_word_under_cursor(true),
+            Action::SearchWordBackward => self.search_word_under_cursor(false),
+            Action::VisualBlock => self.enter_visual(Mode::VisualBlock),
+            Action::Visual => self.enter_visual(Mode::Visual),
+            Action::VisualLine => self.enter_visual(Mode::VisualLine),
+            Action::DeleteChar => self.delete_char_under_cursor(n),
+            Action::ToggleCase => self.toggle_case_under_cursor(n),
+            Action::ReplaceMode => {
+                self.replaced.clear();
+                self.mode = Mode::Replace;
+            }
+            Action::RepeatChange => self.repeat_change(n)?,
+            Action::DeleteToEnd => {
+                self.delete_to_end_of_line();
+            }
+            Action::ChangeToEnd => {
+                let deleted = self.delete_to_end_of_line();
+                self.type_replacement(deleted);
+            }
+            Action::ChangeLines => self.change_lines(n),
+            // Multi-key commands keep the count for when they complete
+            Action::Goto => {
+                self.pending = Some(Pending::Goto);
+                self.pending_count = count;
+            }
+            Action::Delete => {
+                self.pending = Some(Pending::Delete);
+                self.pending_count = count;
+            }
+            Action::Change => {
+                self.pending = Some(Pending::Change);
+                self.pending_count = count;
+            }
+            Action::Yank => {
+                self.pending = Some(Pending::Yank);
+                self.pending_count = count;
+            }
+            Action::Indent => {
+                self.pending = Some(Pending::Indent);
+                self.pending_count = count;
+            }
+            Action::Dedent => {
+                self.pending = Some(Pending::Dedent);
+                self.pending_count = count;
+            }
+            Action::ReplaceChar => {
+                self.pending = Some(Pending::Replace);
+                self.pending_count = count;
+            }
+            Action::Register => {
+                self.pending = Some(Pending::Register);
+                self.pending_count = count;
+            }
+            Action::JoinLines => self.join_lines(n),
+            Action::PasteAfter => self.paste(true, n),
+            Action::PasteBefore => self.paste(false, n),
+            action => {
+                self.handle_motion(action, count);
+            }
+        }
+        if self.pending.is_none() {
+            self.use_clipboard = false;
+        }
+        Ok(())
+    }
+
+    fn handle_visual_mode(&mut self, key: KeyEvent) -> Result<()> {
+        if self.accumulate_count(key) {
+            return Ok(());
+        }
+
+        let count = self.take_count();
+        if let Some(pending) = self.pending.take() {
+            self.handle_pending(pending, key, count);
+            return Ok(());
+        }
+
+        match key.code {
+            KeyCode::Esc => self.mode = Mode::Normal,
+            // Pressing the key of the current Visual mode leaves it, the other
+            // one switches
+            KeyCode::Char('v') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.mode = if self.mode == Mode::VisualBlock {
+                    Mode::Normal
+                } else {
+                    Mode::VisualBlock
+                }
+            }
+            // Other Ctrl keys can only be motions, like the page scrolls
+            _ if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                if let Some(action) = self.keymap.get(Mode::Normal, key) {
+                    self.handle_motion(action, count);
+                }
+            }
+            KeyCode::Char('v') if self.mode == Mode::Visual => self.mode = Mode::Normal,
+            KeyCode::Char('V') if self.mode == Mode::VisualLine => self.mode = Mode::Normal,
+            KeyCode::Char('v') => self.mode = Mode::Visual,
+            KeyCode::Char('V') => self.mode = Mode::VisualLine,
+            KeyCode::Char('I') if self.mode == Mode::VisualBlock => self.block_insert(),
+            KeyCode::Char('d') | KeyCode::Char('x') => self.delete_selection(),
+            KeyCode::Char('y') => self.yank_selection(),
+            KeyCode::Char(':') => {
+                // Like Vim, the command runs on the selected lines through
+                // the `<` and `>` marks
+                let (start, end) = self.selection();
+                self.buf.marks.insert('<', start);
+                self.buf.marks.insert('>', end);
+                self.command_buffer = "'<,'>".to_string();
+                self.mode = Mode::Command;
+            }
+            KeyCode::Char(c @ ('>' | '<')) => {
+                // A count shifts the selection by that many levels
+                let (y, lines) = self.selected_lines();
+                for _ in 0..count.unwrap_or(1) {
+                    self.shift_lines(y, lines, c == '>');
+                }
+                self.move_to_line(y);
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char('g') => {
+                self.pending = Some(Pending::Goto);
+                self.pending_count = count;
+            }
+            KeyCode::Char('"') => {
+                self.pending = Some(Pending::Register);
+                self.pending_count = count;
+            }
+            // Motions are bound along with the Normal mode keys
+            _ => {
+                if let Some(action) = self.keymap.get(Mode::Normal, key) {
+                    self.handle_motion(action, count);
+                }
+            }
+        }
+        if self.pending.is_none() {
+            self.use_clipboard = false;
+        }
+        Ok(())
+    }
+
+    // Add a digit key to the count before a command or the one after an
+    // operator; a leading 0 is the start-of-line motion rather than part of a
+    // count
+    fn accumulate_count(&mut self, key: KeyEvent) -> bool {
+        // Commands like `r`, `f`, `q` and `m` take the digit itself; only an
+        // operator can be followed by a count
+        let count = match self.pending {
+            None => &mut self.pending_count,
+            Some(Pending::Delete | Pending::Change | Pending::Yank) => &mut self.operator_count,
+            Some(_) => return false,
+        };
+        let KeyCode::Char(c @ '0'..='9') = key.code else {
+            return false;
+        };
+        if c == '0' && count.is_none() {
+            return false;
+        }
+        let digit = c.to_digit(10).unwrap_or(0) as usize;
+        *count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+        true
+    }
+
+    // The count for the key being handled: `2d3w` deletes six words
+    fn take_count(&mut self) -> Option<usize> {
+        match (self.pending_count.take(), self.operator_count.take()) {
+            (None, None) => None,
+            (before, after) => Some(before.unwrap_or(1).saturating_mul(after.unwrap_or(1))),
+        }
+    }
+
+    // Cursor motions shared by Normal and Visual mode; returns whether
+    // `action` was one
+    fn handle_motion(&mut self, action: Action, count: Option<usize>) -> bool {
+        let n = count.unwrap_or(1);
+        match action {
+            Action::MoveLeft => self.repeat_motion(n, Self::move_cursor_left),
+            Action::MoveDown => self.repeat_motion(n, Self::move_cursor_down),
+            Action::MoveUp => self.repeat_motion(n, Self::move_cursor_up),
+            Action::MoveRight => self.repeat_motion(n, Self::move_cursor_right),
+            Action::WordForward => self.repeat_motion(n, Self::move_word_forward),
+            Action::WordBackward => self.repeat_motion(n, Self::move_word_backward),
+            Action::ParagraphForward => self.repeat_motion(n, Self::move_paragraph_forward),
+            Action::ParagraphBackward => self.repeat_motion(n, Self::move_paragraph_backward),
+            Action::LineStart => self.buf.cursor_x = 0,
+            Action::LineEnd => {
+                // In Visual mode `$` takes in the line break too
+                self.buf.cursor_x = if self.mode == Mode::Visual {
+                    self.buf.content.line_len(self.buf.cursor_y)
+                } else {
+                    self.max_cursor_x()
+                };
+                self.buf.preferred_col = usize::MAX;
+                self.keep_preferred_col = true;
+            }
+            Action::FirstNonBlank => {
+                self.buf.cursor_x = first_non_blank(&self.buf.content.line(self.buf.cursor_y))
+            }
+            // A count gives the lines to scroll for half pages, and the number
+            // of pages for whole ones; Vim keeps two lines of the old page in
+            // view when paging
+            Action::ScrollHalfDown | Action::ScrollHalfUp => {
+                let lines = count.unwrap_or(self.text_area().1.div_ceil(2));
+                self.scroll_view(lines, action == Action::ScrollHalfDown);
+            }
+            Action::ScrollPageDown | Action::ScrollPageUp => {
+                let lines = self.text_area().1.saturating_sub(2).max(1);
+                self.scroll_view(lines * n, action == Action::ScrollPageDown);
+            }
+            Action::GotoLine => {
+                self.remember_jump();
+                self.goto_line(count.unwrap_or(self.buf.content.line_count()))
+            }
+            Action::MatchBracket => {
+                if let Some((x, y)) = self.find_matching_bracket() {
+                    self.remember_jump();
+                    (self.buf.cursor_x, self.buf.cursor_y) = (x, y);
+                }
+            }
+            Action::Fold => self.pending = Some(Pending::Fold),
+            Action::GotoMark => self.pending = Some(Pending::GotoMark),
+            Action::GotoMarkLine => self.pending = Some(Pending::GotoMarkLine),
+            Action::FindForward
+            | Action::FindBackward
+            | Action::TillForward
+            | Action::TillBackward => {
+                self.pending = Some(Pending::Find {
+                    forward: matches!(action, Action::FindForward | Action::TillForward),
+                    till: matches!(action, Action::TillForward | Action::TillBackward),
+                });
+                self.pending_count = count;
+            }
+            Action::RepeatFind | Action::RepeatFindReverse => {
+                if let Some(find) = self.last_find {
+                    // `,` goes the other way
+                    let forward = find.forward == (action == Action::RepeatFind);
+                    self.find_char(CharFind { forward, ..find }, n, true);
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // Any key that doesn't complete the command (including Esc) cancels it
+    fn handle_pending(&mut self, pending: Pending, key: KeyEvent, count: Option<usize>) {
+        match (pending, key.code) {
+            (Pending::Delete, KeyCode::Char('d')) => self.delete_lines(count.unwrap_or(1)),
+            (Pending::Delete, KeyCode::Char('w')) => {
+                self.delete_words(count.unwrap_or(1));
+            }
+            (Pending::Delete | Pending::Change | Pending::Yank, KeyCode::Char(c @ ('}' | '{'))) => {
+                self.operate_on_paragraphs(pending, c == '}', count.unwrap_or(1))
+            }
+            (Pending::Find { forward, till }, KeyCode::Char(target)) => {
+                let find = CharFind {
+                    target,
+                    forward,
+                    till,
+                };
+                self.last_find = Some(find);
+                self.find_char(find, count.unwrap_or(1), false);
+            }
+            (Pending::Indent, KeyCode::Char('>')) => {
+                let y = self.buf.cursor_y;
+                self.shift_lines(y, count.unwrap_or(1), true)
+            }
+            (Pending::Dedent, KeyCode::Char('<')) => {
+                let y = self.buf.cursor_y;
+                self.shift_lines(y, count.unwrap_or(1), false)
+            }
+            (Pending::Delete, KeyCode::Char('$')) => {
+                self.delete_to_end_of_line();
+            }
+            (Pending::Delete, KeyCode::Char('0')) => self.delete_to_start_of_line(),
+            (Pending::Delete, KeyCode::Char('G')) => {
+                let last = self.buf.content.line_count();
+                self.delete_to_line(count.unwrap_or(last))
+            }
+            (Pending::Delete, KeyCode::Char('g')) => {
+                self.pending = Some(Pending::DeleteGoto);
+                self.pending_count = count;
+            }
+            (Pending::DeleteGoto, KeyCode::Char('g')) => self.delete_to_line(count.unwrap_or(1)),
+            (Pending::Delete, KeyCode::Char('i')) => self.pending = Some(Pending::DeleteInner),
+            (Pending::Change, KeyCode::Char('i')) => self.pending = Some(Pending::ChangeInner),
+            (Pending::DeleteInner, KeyCode::Char(c)) => self.delete_inner(c, false),
+            (Pending::ChangeInner, KeyCode::Char(c)) => self.delete_inner(c, true),
+            (Pending::Change, KeyCode::Char('w')) => {
+                let deleted = self.delete_words(count.unwrap_or(1));
+                self.type_replacement(deleted);
+            }
+            (Pending::Change, KeyCode::Char('c')) => self.change_lines(count.unwrap_or(1)),
+            (Pending::Yank, KeyCode::Char('y')) => self.yank_lines(count.unwrap_or(1)),
+            (Pending::Goto, KeyCode::Char('g')) => {
+                self.remember_jump();
+                self.goto_line(count.unwrap_or(1))
+            }
+            (Pending::Goto, KeyCode::Char('v')) => self.reselect(),
+            (Pending::Goto, KeyCode::Char('j')) => {
+                self.repeat_motion(count.unwrap_or(1), Self::move_row_down)
+            }
+            (Pending::Goto, KeyCode::Char('k')) => {
+                self.repeat_motion(count.unwrap_or(1), Self::move_row_up)
+            }
+            (Pending::SetMark, KeyCode::Char(c)) if c.is_ascii_alphabetic() => {
+                let position = (self.buf.cursor_x, self.buf.cursor_y);
+                self.buf.marks.insert(c, position);
+            }
+            (Pending::Fold, KeyCode::Char('a')) => self.toggle_fold(),
+            (Pending::GotoMark, KeyCode::Char(c)) => self.goto_mark(c, false),
+            (Pending::GotoMarkLine, KeyCode::Char(c)) => self.goto_mark(c, true),
+            (Pending::Replace, KeyCode::Char(c)) => self.replace_chars(c, count.unwrap_or(1)),
+            (Pending::Record, KeyCode::Char(c)) if c.is_ascii_alphanumeric() => {
+                self.recording = Some((c, Vec::new()));
+            }
+            (Pending::Play, KeyCode::Char(c)) => self.play_macro(c, count.unwrap_or(1)),
+            // The register applies to the command typed next
+            (Pending::Register, KeyCode::Char('+' | '*')) => {
+                self.use_clipboard = true;
+                self.pending_count = count;
+                return;
+            }
+            (Pending::Register, KeyCode::Char(c)) => {
+                self.set_status(format!("Unknown register: {}", c))
+            }
+            _ => (),
+        }
+        if self.pending.is_none() {
+            self.use_clipboard = false;
+        }
+    }
+
+    // Feed the keys recorded in `register` (or the last one played, for `@`)
+    // back through the key handlers `count` times
+    fn play_macro(&mut self, register: char, count: usize) {
+        let register = match register {
+            '@' => match self.last_macro {
+                Some(register) => register,
+                None => {
+                    self.set_status("No previously used register");
+                    return;
+                }
+            },
+            register => register,
+        };
+        let Some(keys) = self.macros.get(&register).cloned() else {
+            self.set_status(format!("Nothing recorded in register {}", register));
+            return;
+        };
+        if self.macro_depth == MACRO_DEPTH_LIMIT {
+            self.set_status("Macro recursion too deep");
+            return;
+        }
+
+        self.last_macro = Some(register);
+        self.macro_depth += 1;
+        'replay: for _ in 0..count {
+            for &key in &keys {
+                if let Err(e) = self.handle_key(key) {
+                    self.set_status(format!("Macro stopped: {}", e));
+                    break 'replay;
+                }
+                if self.quit {
+                    break 'replay;
+                }
+            }
+        }
+        self.macro_depth -= 1;
+    }
+
+    // Move to the `count`th `find.target` along the current line, or just
+    // short of it; nothing moves when there aren't that many
+    fn find_char(&mut self, find: CharFind, count: usize, repeat: bool) {
+        let chars: Vec<char> = self.buf.content.line(self.buf.cursor_y).chars().collect();
+        let x = self.buf.cursor_x;
+        // Repeating a `t` would find the char it stopped before again
+        let skip = usize::from(find.till && repeat);
+        let found = if find.forward {
+            (x + 1 + skip..chars.len())
+                .filter(|&i| chars[i] == find.target)
+                .nth(count - 1)
+                .map(|i| if find.till { i - 1 } else { i })
+        } else {
+            (0..x.saturating_sub(skip))
+                .rev()
+                .filter(|&i| chars[i] == find.target)
+                .nth(count - 1)
+                .map(|i| if find.till { i + 1 } else { i })
+        };
+        if let Some(i) = found {
+            self.buf.cursor_x = i;
+        }
+    }
+
+    // Run a motion `count` times, stopping early once it no longer moves
+    fn repeat_motion(&mut self, count: usize, motion: fn(&mut Self)) {
+        for _ in 0..count {
+            let before = (self.buf.cursor_x, self.buf.cursor_y);
+            motion(self);
+            if (self.buf.cursor_x, self.buf.cursor_y) == before {
+                break;
+            }
+        }
+    }
+
+    fn handle_insert_mode(&mut self, key: KeyEvent) -> Result<()> {
+        let last_typed = self.last_typed.take();
+        let completion = self.completion.take();
+        if let Some(action) = self.keymap.get(Mode::Insert, key) {
+            match action {
+                Action::CompleteNext => self.complete(completion, true),
+                Action::CompletePrevious => self.complete(completion, false),
+                Action::LeaveInsert => self.leave_insert_mode(),
+                Action::Backspace => self.delete_char(),
+                Action::Newline => self.insert_newline(),
+                Action::Tab => self.insert_tab(),
+                Action::DeleteWordBackward => {
+                    let (x, y) = self.prev_word_start(self.buf.cursor_x, self.buf.cursor_y);
+                    // Stop at the start of the line instead of joining it
+                    let x = if y == self.buf.cursor_y { x } else { 0 };
+                    self.delete_back_to(x);
+                }
+                Action::DeleteToLineStart => {
+                    // Keep the indentation, unless there's nothing else to
+                    // delete
+                    let indent = first_non_blank(&self.buf.content.line(self.buf.cursor_y));
+                    let x = if self.buf.cursor_x > indent {
+                        indent
+                    } else {
+                        0
+                    };
+                    self.delete_back_to(x);
+                }
+                // Config checks only Insert mode actions are bound here
+                _ => (),
+            }
+            return Ok(());
+        }
+        if let KeyCode::Char(c) = key.code {
+            if self.completes_escape(c, last_typed) {
+                // Take back the first char of the sequence, without a
+                // separate undo step; a read-only buffer never got it
+                if !self.buf.read_only {
+                    let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+                    self.buf.content.remove((x - 1, y), (x, y));
+                    self.buf.cursor_x -= 1;
+                }
+                self.leave_insert_mode();
+            } else {
+                self.insert_char(c);
+                self.last_typed = Some((c, Instant::now()));
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_replace_mode(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Esc => self.leave_insert_mode(),
+            KeyCode::Char(c) => self.overwrite_char(c),
+            KeyCode::Backspace => self.restore_replaced(),
+            KeyCode::Enter => {
+                // Like Vim, a line break is inserted rather than replacing,
+                // and Backspace doesn't go back past it
+                self.insert_newline();
+                self.replaced.clear();
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Replace the char under the cursor with `c`, or add it at the end of
+    // the line, and move past it
+    fn overwrite_char(&mut self, c: char) {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        if self.buf.insert_group_end != Some((x, y)) && !self.record_undo() {
+            return;
+        }
+        let old = self.buf.content.line(y).chars().nth(x);
+        if old.is_some() {
+            self.buf.content.remove((x, y), (x + 1, y));
+        }
+        self.buf.content.insert_char(y, x, c);
+        self.replaced.push(old);
+        self.buf.cursor_x += 1;
+        self.buf.dirty = true;
+        self.buf.insert_group_end = Some((self.buf.cursor_x, y));
+    }
+
+    // Backspace in Replace mode: bring back what the last typed char
+    // overwrote, or just step left past chars that were there already
+    fn restore_replaced(&mut self) {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        if x == 0 {
+            return;
+        }
+        if let Some(old) = self.replaced.pop() {
+            self.buf.content.remove((x - 1, y), (x, y));
+            if let Some(old) = old {
+                self.buf.content.insert_char(y, x - 1, old);
+            }
+            self.buf.insert_group_end = Some((x - 1, y));
+        }
+        self.buf.cursor_x -= 1;
+    }
+
+    // Like Vim, leaving Insert mode steps back onto the last char typed
+    fn leave_insert_mode(&mut self) {
+        self.finish_block_insert();
+        self.buf.insert_group_end = None;
+        self.mode = Mode::Normal;
+        self.move_cursor_left();
+    }
+
+    // Whether `c` is the second char of the escape sequence, typed in time
+    // right after the first one
+    fn completes_escape(&self, c: char, last_typed: Option<(char, Instant)>) -> bool {
+        let (Some((first, second)), Some((typed, at))) = (self.escape_sequence, last_typed) else {
+            return false;
+        };
+        let x = self.buf.cursor_x;
+        c == second
+            && typed == first
+            && at.elapsed() <= self.escape_timeout
+            && x > 0
+            && self.buf.content.line(self.buf.cursor_y).chars().nth(x - 1) == Some(first)
+    }
+
+    fn handle_save_as_mode(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Esc => {
+                self.prompt_buffer.clear();
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Char(c) => self.prompt_buffer.push(c),
+            KeyCode::Backspace => {
+                self.prompt_buffer.pop();
+            }
+            KeyCode::Enter => {
+                self.mode = Mode::Normal;
+                let name = std::mem::take(&mut self.prompt_buffer);
+                if !name.is_empty() {
+                    self.buf.set_filename(PathBuf::from(name));
+                    self.save_or_report();
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn handle_command_mode(&mut self, key: KeyEvent) -> Result<()> {
+        match key.code {
+            KeyCode::Esc => {
+                self.command_buffer.clear();
+                self.history_index = None;
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Up if self.mode == Mode::Command => self.recall_command(true),
+            KeyCode::Down if self.mode == Mode::Command => self.recall_command(false),
+            KeyCode::Char(c) => self.command_buffer.push(c),
+            KeyCode::Backspace if self.command_buffer.is_empty() => {
+                self.history_index = None;
+                self.mode = Mode::Normal;
+            }
+            KeyCode::Backspace => {
+                self.command_buffer.pop();
+            }
+            KeyCode::Enter => {
+                let mode = std::mem::replace(&mut self.mode, Mode::Normal);
+                let input = std::mem::take(&mut self.command_buffer);
+                self.history_index = None;
+                if mode == Mode::Search {
+                    self.search(input);
+                } else {
+                    let cmd = input.trim();
+                    if !cmd.is_empty()
+                        && self.command_history.last().map(String::as_str) != Some(cmd)
+                    {
+                        self.command_history.push(cmd.to_string());
+                    }
+                    self.execute_command(cmd);
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    // Step back (`older`) or forward through the command history, ending up
+    // back at what was typed
+    fn recall_command(&mut self, older: bool) {
+        let len = self.command_history.len();
+        let index = match (self.history_index.as_ref().map(|(i, _)| *i), older) {
+            (None, true) if len > 0 => {
+                let draft = std::mem::take(&mut self.command_buffer);
+                self.history_index = Some((len, draft));
+                len - 1
+            }
+            (Some(i), true) => i.saturating_sub(1),
+            (Some(i), false) if i + 1 < len => i + 1,
+            (Some(_), false) => {
+                if let Some((_, draft)) = self.history_index.take() {
+                    self.command_buffer = draft;
+                }
+                return;
+            }
+            _ => return,
+        };
+        if let Some((i, _)) = self.history_index.as_mut() {
+            *i = index;
+        }
+        self.command_buffer = self.command_history[index].clone();
+    }
+
+    fn execute_command(&mut self, cmd: &str) {
+        // `'<,'>` limits a command to the lines of the last Visual selection
+        let (lines, cmd) = match cmd.strip_prefix("'<,'>") {
+            Some(rest) => match (self.buf.marks.get(&'<'), self.buf.marks.get(&'>')) {
+                (Some(&(_, start)), Some(&(_, end))) => {
+                    let last = self.buf.content.line_count() - 1;
+                    (Some(start.min(last)..end.min(last) + 1), rest.trim_start())
+                }
+                _ => {
+                    self.set_status("Mark not set");
+                    return;
+                }
+            },
+            None => (None, cmd),
+        };
+        if lines.is_some() && !cmd.starts_with("sort") {
+            self.set_status(format!("Range not supported: {}", cmd));
+            return;
+        }
+
+        // Shell commands and substitutions are parsed whole, since they may
+        // contain spaces
+        if let Some(command) = cmd.strip_prefix("%!") {
+            self.filter_buffer(command.trim());
+            return;
+        }
+        if let Some(command) = cmd.strip_prefix('!') {
+            if let Err(e) = self.run_shell(command.trim()) {
+                self.set_status(format!("Error running command: {}", e));
+            }
+            return;
+        }
+        if let Some(rest) = cmd.strip_prefix("%s/") {
+            self.substitute(rest, true);
+            return;
+        }
+        if let Some(rest) = cmd.strip_prefix("s/") {
+            self.substitute(rest, false);
+            return;
+        }
+
+        let (name, arg) = match cmd.split_once(' ') {
+            Some((name, arg)) => (name, Some(arg.trim())),
+            None => (cmd, None),
+        };
+
+        match name {
+            "" => (),
+            "$" => {
+                self.remember_jump();
+                self.jump_to_line(self.buf.content.line_count())
+            }
+            _ if name.chars().all(|c| c.is_ascii_digit()) => {
+                self.remember_jump();
+                self.jump_to_line(name.parse().unwrap_or(usize::MAX))
+            }
+            "w" | "wq" | "w!" | "wq!" => {
+                if let Some(path) = arg.filter(|a| !a.is_empty()) {
+                    self.buf.set_filename(PathBuf::from(path));
+                }
+                // `!` writes a read-only buffer anyway
+                if name.ends_with('!') {
+                    self.buf.read_only = false;
+                }
+                self.save_or_report();
+                if name.starts_with("wq") && !self.buf.dirty && self.mode == Mode::Normal {
+                    self.quit = true;
+                }
+            }
+            "q" => {
+                if self.buf.dirty {
+                    self.set_status("No write since last change (add ! to override)");
+                } else if let Some(other) = self.buffers.iter().find(|b| b.dirty) {
+                    self.set_status(format!(
+                        "No write since last change for buffer \"{}\" (add ! to override)",
+                        other.name()
+                    ));
+                } else {
+                    self.quit = true;
+                }
+            }
+            "q!" => self.quit = true,
+            "e" => match arg {
+                Some(path) if !path.is_empty() => self.edit_file(path),
+                _ => self.set_status("Usage: :e <path>"),
+            },
+            "view" => match arg {
+                Some(path) if !path.is_empty() => {
+                    self.edit_file(path);
+                    if self.buf.filename.as_deref() == Some(Path::new(path)) {
+                        self.buf.read_only = true;
+                    }
+                }
+                _ => self.set_status("Usage: :view <path>"),
+            },
+            "r" => match arg {
+                Some(path) if !path.is_empty() => self.read_file(Path::new(path)),
+                _ => self.set_status("Usage: :r <path>"),
+            },
+            "bn" => self.switch_buffer((self.active + 1) % self.buffer_count()),
+            "bp" => {
+                let count = self.buffer_count();
+                self.switch_buffer((self.active + count - 1) % count)
+            }
+            "noh" | "nohlsearch" => self.highlight_matches = false,
+            "stats" => self.show_stats(),
+            "date" | "time" => {
+                let default = if name == "date" {
+                    &self.date_format
+                } else {
+                    &self.time_format
+                };
+                let format = arg.filter(|a| !a.is_empty()).unwrap_or(default).to_string();
+                self.insert_timestamp(&format);
+            }
+            "recent" => {
+                if let Err(e) = self.open_recent(arg) {
+                    self.set_status(format!("Error listing recent files: {}", e));
+                }
+            }
+            // Gutter markers, mostly for tools driving the editor
+            "marker" => match arg.and_then(|arg| arg.chars().next()) {
+                Some(symbol) => {
+                    let marker = Marker {
+                        symbol,
+                        color: Color::Red,
+                    };
+                    self.buf.content.set_marker(self.buf.cursor_y, marker);
+                }
+                None => self.set_status("Usage: :marker <char>"),
+            },
+            "clearmarkers" => self.buf.content.clear_markers(),
+            "sort" | "sort!" => {
+                let lines = lines.unwrap_or(0..self.buf.content.line_count());
+                match arg.unwrap_or("") {
+                    "" => self.sort_lines(lines, name == "sort!", false),
+                    "n" => self.sort_lines(lines, name == "sort!", true),
+                    other => self.set_status(format!("Invalid argument: {}", other)),
+                }
+            }
+            "bd" => self.close_buffer(false),
+            "bd!" => self.close_buffer(true),
+            "set" => match arg {
+                Some(options) if !options.trim().is_empty() => {
+                    for option in options.split_whitespace() {
+                        self.set_option(option);
+                    }
+                }
+                _ => self.set_status("Usage: :set <option>[=value]"),
+            },
+            _ => self.set_status(format!("Not an editor command: {}", cmd)),
+        }
+    }
+
+    // Sizes of the buffer and where the cursor is in it, for `:stats` and
+    // Ctrl-G. Words are runs of one char class, as the `w` motion sees them.
+    fn show_stats(&mut self) {
+        let (mut chars, mut words, mut byte) = (0, 0, 0);
+        let ending = self.buf.line_ending.as_str().len();
+        for (y, line) in self.buf.content.lines().enumerate() {
+            chars += char_count(&line);
+            let mut prev = CharClass::Whitespace;
+            for c in line.chars() {
+                let class = char_class(c);
+                if class != CharClass::Whitespace && class != prev {
+                    words += 1;
+                }
+                prev = class;
+            }
+            match y.cmp(&self.buf.cursor_y) {
+                std::cmp::Ordering::Less => byte += line.len() + ending,
+                std::cmp::Ordering::Equal => {
+                    byte += line
+                        .char_indices()
+                        .nth(self.buf.cursor_x)
+                        .map_or(line.len(), |(i, _)| i)
+                }
+                std::cmp::Ordering::Greater => (),
+            }
+        }
+        self.set_status(format!(
+            "{} lines, {} words, {} chars; {}:{}, byte {}",
+            self.buf.content.line_count(),
+            words,
+            chars,
+            self.buf.cursor_y + 1,
+            self.buf.cursor_x + 1,
+            byte + 1
+        ));
+    }
+
+    // Insert the lines of `path` below the cursor line, like Vim's `:r`
+    fn read_file(&mut self, path: &Path) {
+        // Opening a buffer treats a missing file as a new one
+        if !path.exists() {
+            self.set_status(format!("Can't open file {}", path.display()));
+            return;
+        }
+        let file = match Buffer::open(path) {
+            Ok((file, _)) => file.content,
+            Err(e) => {
+                self.set_status(format!("Error reading {}: {}", path.display(), e));
+                return;
+            }
+        };
+        let lines: Vec<String> = file.lines().collect();
+        if lines == [""] {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+        let y = self.buf.cursor_y + 1;
+        self.buf.content.insert_lines(y, &lines);
+        self.jump_to_line(y + 1);
+        self.buf.dirty = true;
+        self.set_status(format!("\"{}\" {} lines", path.display(), lines.len()));
+    }
+
+    // Insert the local time, formatted with the strftime-style `format`, at
+    // the cursor and move past it
+    fn insert_timestamp(&mut self, format: &str) {
+        let mut text = String::new();
+        if write!(text, "{}", Local::now().format(format)).is_err() {
+            self.set_status(format!("Invalid time format: {}", format));
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+        let (x, y) = self.insert_text(self.buf.cursor_x, self.buf.cursor_y, &text);
+        (self.buf.cursor_x, self.buf.cursor_y) = (x, y);
+        // At the end of the line Normal mode keeps it on the last char
+        self.clamp_cursor_x();
+        self.buf.dirty = true;
+    }
+
+    // `:!`: run `command` on the terminal the editor came from, so it can be
+    // interactive, and wait for a key before going back to the buffer
+    fn run_shell(&mut self, command: &str) -> Result<()> {
+        if command.is_empty() {
+            self.set_status("Usage: :!<command>");
+            return Ok(());
+        }
+        self.suspend_screen()?;
+        let status = Command::new("sh").arg("-c").arg(command).status();
+        print!("\nPress any key to continue");
+        stdout().flush()?;
+        terminal::enable_raw_mode()?;
+        while !matches!(event::read()?, Event::Key(_)) {}
+        self.resume_screen()?;
+        match status?.code() {
+            Some(0) => (),
+            Some(code) => self.set_status(format!("shell returned {}", code)),
+            None => self.set_status("shell command was killed"),
+        }
+        Ok(())
+    }
+
+    // Hand the terminal back as it was before the editor started, e.g. for
+    // a shell command to use
+    fn suspend_screen(&mut self) -> Result<()> {
+        self.restore_cursor_shape()?;
+        leave_screen()
+    }
+
+    fn resume_screen(&mut self) -> Result<()> {
+        enter_screen()?;
+        // Everything on screen has to be drawn again
+        self.frame.clear();
+        Ok(())
+    }
+
+    // `:recent` lists the recently used files and asks which one to open;
+    // `:recent N` opens the Nth straight away
+    fn open_recent(&mut self, arg: Option<&str>) -> Result<()> {
+        let files = recent::load();
+        if files.is_empty() {
+            self.set_status("No recent files");
+            return Ok(());
+        }
+        let choice = match arg.filter(|a| !a.is_empty()) {
+            Some(arg) => arg.to_string(),
+            None => {
+                self.suspend_screen()?;
+                println!();
+                for (i, file) in files.iter().enumerate() {
+                    println!("{:>2}: {}", i + 1, file.display());
+                }
+                print!("Type number and <Enter> (empty cancels): ");
+                stdout().flush()?;
+                terminal::enable_raw_mode()?;
+                let choice = read_number()?;
+                self.resume_screen()?;
+                choice
+            }
+        };
+        if choice.is_empty() {
+            return Ok(());
+        }
+        match choice
+            .parse::<usize>()
+            .ok()
+            .and_then(|n| files.get(n.wrapping_sub(1)))
+        {
+            Some(file) => {
+                // Files under the working directory are opened by their
+                // relative path, matching a buffer opened that way
+                let cwd = std::env::current_dir()?;
+                let file = file.strip_prefix(&cwd).unwrap_or(file);
+                self.edit_file(&file.to_string_lossy());
+            }
+            None => self.set_status(format!("No recent file {}", choice)),
+        }
+        Ok(())
+    }
+
+    // `:%!`: replace the buffer with what `command` prints when given the
+    // buffer on stdin. A failing command leaves the buffer alone.
+    fn filter_buffer(&mut self, command: &str) {
+        if command.is_empty() {
+            self.set_status("Usage: :%!<command>");
+            return;
+        }
+        let mut text = self.buf.content.to_string_with("\n");
+        text.push('\n');
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                // Fed from another thread, so a command writing a lot before
+                // it's read all its input can't block us both
+                let mut input = child.stdin.take().expect("stdin is piped");
+                let writer = std::thread::spawn(move || input.write_all(text.as_bytes()));
+                let output = child.wait_with_output()?;
+                // A command that exits without reading it all breaks the pipe
+                let _ = writer.join();
+                Ok(output)
+            });
+        let output = match output {
+            Ok(output) => output,
+            Err(e) => {
+                self.set_status(format!("Error running command: {}", e));
+                return;
+            }
+        };
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            let message = match stderr.lines().find(|l| !l.trim().is_empty()) {
+                Some(line) => line.trim().to_string(),
+                None => format!("shell returned {}", output.status.code().unwrap_or(-1)),
+            };
+            self.set_status(message);
+            return;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout).replace("\r\n", "\n");
+        let filtered = stdout.strip_suffix('\n').unwrap_or(&stdout);
+        let count = filtered.lines().count();
+        if self.buf.content.to_string_with("\n") != filtered {
+            if !self.record_undo() {
+                return;
+            }
+            self.buf.content = TextBuffer::from_text(filtered);
+            self.buf.cursor_y = self.buf.cursor_y.min(self.buf.content.line_count() - 1);
+            self.clamp_cursor_x();
+            self.buf.dirty = true;
+        }
+        self.set_status(format!("{} lines filtered", count));
+    }
+
+    // Sort `lines` alphabetically, or by their leading integer, putting the
+    // cursor on the first of them
+    fn sort_lines(&mut self, lines: Range<usize>, reverse: bool, numeric: bool) {
+        let original: Vec<String> = lines.clone().map(|y| self.buf.content.line(y)).collect();
+        let mut sorted = original.clone();
+        if numeric {
+            // Lines without a number go first, like in Vim
+            sorted.sort_by_key(|line| leading_number(line));
+        } else {
+            sorted.sort();
+        }
+        if reverse {
+            sorted.reverse();
+        }
+        if sorted != original {
+            if !self.record_undo() {
+                return;
+            }
+            for (y, line) in lines.clone().zip(&sorted) {
+                self.buf.content.set_line(y, line);
+            }
+            self.buf.dirty = true;
+        }
+        self.jump_to_line(lines.start + 1);
+    }
+
+    fn buffer_count(&self) -> usize {
+        self.buffers.len() + 1
+    }
+
+    // Make buffer `index` of the full list the current one
+    fn switch_buffer(&mut self, index: usize) {
+        if index == self.active {
+            return;
+        }
+        let current = std::mem::take(&mut self.buf);
+        self.buffers.insert(self.active, current);
+        self.buf = self.buffers.remove(index);
+        self.active = index;
+    }
+
+    // Switch to `path`, opening it in a new buffer unless it already has one
+    fn edit_file(&mut self, path: &str) {
+        let path = PathBuf::from(path);
+        if self.buf.filename.as_ref() == Some(&path) {
+            return;
+        }
+        if let Some(i) = self
+            .buffers
+            .iter()
+            .position(|b| b.filename.as_ref() == Some(&path))
+        {
+            self.switch_buffer(if i < self.active { i } else { i + 1 });
+            return;
+        }
+
+        match Buffer::open(&path) {
+            Ok((buf, warning)) => {
+                let _ = recent::record(&path);
+                self.buffers.push(buf);
+                self.switch_buffer(self.buffer_count() - 1);
+                if let Some(warning) = warning {
+                    self.set_status(warning);
+                }
+            }
+            Err(e) => self.set_status(format!("Error opening {}: {}", path.display(), e)),
+        }
+    }
+
+    // Close the current buffer and show the next one, leaving an empty buffer
+    // when it was the last
+    fn close_buffer(&mut self, force: bool) {
+        if self.buf.dirty && !force {
+            self.set_status("No write since last change (add ! to override)");
+            return;
+        }
+        self.buf.remove_swap();
+        if self.buffers.is_empty() {
+            self.buf = Buffer::new();
+            return;
+        }
+        let next = self.active.min(self.buffers.len() - 1);
+        self.buf = self.buffers.remove(next);
+        self.active = next;
+    }
+
+    // Run `old/new/flags` on the current line or the whole buffer
+    fn substitute(&mut self, spec: &str, whole_buffer: bool) {
+        let parts = split_unescaped(spec, '/');
+        let pattern = match parts.first().filter(|p| !p.is_empty()) {
+            Some(pattern) => pattern.clone(),
+            None => match &self.last_search {
+                Some(pattern) => pattern.clone(),
+                None => {
+                    self.set_status("No previous search pattern");
+                    return;
+                }
+            },
+        };
+        let replacement = parts.get(1).cloned().unwrap_or_default();
+        let flags = parts.get(2).map_or("", String::as_str);
+        if parts.len() > 3 || flags.chars().any(|c| c != 'g') {
+            self.set_status(format!("Trailing characters: {}", flags));
+            return;
+        }
+        let global = flags.contains('g');
+
+        let lines = if whole_buffer {
+            0..self.buf.content.line_count()
+        } else {
+            self.buf.cursor_y..self.buf.cursor_y + 1
+        };
+        let mut substitutions = 0;
+        let mut changed_lines = Vec::new();
+        for y in lines {
+            let count = match self.buf.content.line(y).matches(pattern.as_str()).count() {
+                0 => continue,
+                n if global => n,
+                _ => 1,
+            };
+            substitutions += count;
+            changed_lines.push(y);
+        }
+
+        let Some(&last_line) = changed_lines.last() else {
+            self.set_status(format!("Pattern not found: {}", pattern));
+            return;
+        };
+        if !self.record_undo() {
+            return;
+        }
+        for y in changed_lines.iter().copied() {
+            let line = self.buf.content.line(y);
+            let replaced = if global {
+                line.replace(pattern.as_str(), &replacement)
+            } else {
+                line.replacen(pattern.as_str(), &replacement, 1)
+            };
+            self.buf.content.set_line(y, &replaced);
+        }
+        self.buf.cursor_y = last_line;
+        self.buf.cursor_x = first_non_blank(&self.buf.content.line(last_line));
+        self.buf.dirty = true;
+        self.set_status(format!(
+            "{} substitution{} on {} line{}",
+            substitutions,
+            if substitutions == 1 { "" } else { "s" },
+            changed_lines.len(),
+            if changed_lines.len() == 1 { "" } else { "s" }
+        ));
+    }
+
+    fn set_option(&mut self, option: &str) {
+        if let Some((name, value)) = option.split_once('=') {
+            match name {
+                "tabstop" | "ts" => match value.parse() {
+                    // A zero tab width would break tab stop maths
+                    Ok(width) if width > 0 => self.tab_width = width,
+                    _ => self.set_status(format!("Invalid value for {}: {}", name, value)),
+                },
+                "scrolloff" | "so" => match value.parse() {
+                    Ok(lines) => self.scrolloff = lines,
+                    Err(_) => self.set_status(format!("Invalid value for {}: {}", name, value)),
+                },
+                // An empty value or 0 turns the ruler off
+                "colorcolumn" | "cc" => match value.parse() {
+                    Ok(col) => self.colorcolumn = Some(col).filter(|&col| col > 0),
+                    Err(_) if value.is_empty() => self.colorcolumn = None,
+                    Err(_) => self.set_status(format!("Invalid value for {}: {}", name, value)),
+                },
+                _ => self.set_status(format!("Unknown option: {}", name)),
+            }
+            return;
+        }
+
+        match option {
+            "number" | "nu" => self.show_line_numbers = true,
+            "nonumber" | "nonu" => self.show_line_numbers = false,
+            "relativenumber" | "rnu" => self.relative_numbers = true,
+            "norelativenumber" | "nornu" => self.relative_numbers = false,
+            "wrap" => self.wrap = true,
+            "nowrap" => self.wrap = false,
+            "bomb" => self.set_bom(true),
+            "nobomb" => self.set_bom(false),
+            "readonly" | "ro" => self.buf.read_only = true,
+            "noreadonly" | "noro" => self.buf.read_only = false,
+            "smartcase" | "scs" => self.smartcase = true,
+            "nosmartcase" | "noscs" => self.smartcase = false,
+            "cursorline" | "cul" => self.cursorline = true,
+            "nocursorline" | "nocul" => self.cursorline = false,
+            "list" => self.list_mode = true,
+            "nolist" => self.list_mode = false,
+            "smarttab" | "sta" => self.smarttab = true,
+            "nosmarttab" | "nosta" => self.smarttab = false,
+            "expandtab" | "et" => self.expand_tabs = true,
+            "noexpandtab" | "noet" => self.expand_tabs = false,
+            "autoindent" | "ai" => self.auto_indent = true,
+            "noautoindent" | "noai" => self.auto_indent = false,
+            _ => self.set_status(format!("Unknown option: {}", option)),
+        }
+    }
+
+    // Changing whether the file starts with a BOM changes the file, like an
+    // edit would
+    fn set_bom(&mut self, bom: bool) {
+        if self.buf.read_only {
+            self.set_status("Buffer is read-only");
+        } else if self.buf.write_bom != bom {
+            self.buf.write_bom = bom;
+            self.buf.dirty = true;
+        }
+    }
+
+    // An empty query repeats the previous search, like Vim
+    fn search(&mut self, query: String) {
+        if !query.is_empty() {
+            self.last_search = Some(query);
+        }
+        self.search_next(true);
+    }
+
+    // `*` and `#`: search for the whole word under the cursor
+    fn search_word_under_cursor(&mut self, forward: bool) {
+        let chars: Vec<char> = self.buf.content.line(self.buf.cursor_y).chars().collect();
+        let is_word = |i: usize| {
+            chars
+                .get(i)
+                .is_some_and(|&c| char_class(c) == CharClass::Word)
+        };
+        let mut start = self.buf.cursor_x;
+        if !is_word(start) {
+            return;
+        }
+        while start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        let mut end = self.buf.cursor_x;
+        while is_word(end) {
+            end += 1;
+        }
+        let word: String = chars[start..end].iter().collect();
+        self.last_search = Some(format!("\\<{}\\>", word));
+        // Searching from the word's start keeps `#` from finding it again
+        self.buf.cursor_x = start;
+        self.search_next(forward);
+    }
+
+    fn search_next(&mut self, forward: bool) {
+        let Some(pattern) = self.last_search.clone() else {
+            self.set_status("No previous search pattern");
+            return;
+        };
+        self.highlight_matches = true;
+
+        match self.find_match(&pattern, forward) {
+            Some((x, y, wrapped)) => {
+                self.remember_jump();
+                self.buf.cursor_x = x;
+                self.buf.cursor_y = y;
+                self.buf.current_match = Some((x, y));
+                let position = self.match_position(&pattern);
+                self.set_status(if !wrapped {
+                    format!(
+                        "{}{} {}",
+                        if forward { '/' } else { '?' },
+                        pattern,
+                        position
+                    )
+                } else if forward {
+                    format!("search hit BOTTOM, continuing at TOP {}", position)
+                } else {
+                    format!("search hit TOP, continuing at BOTTOM {}", position)
+                });
+            }
+            None => self.set_status(format!("Pattern not found: {}", pattern)),
+        }
+    }
+
+    // Which match of `pattern` the cursor is on and how many there are, like
+    // `[3/17]`. Counting stops past `MATCH_COUNT_LIMIT` so huge files stay
+    // quick.
+    fn match_position(&self, pattern: &str) -> String {
+        let ignore_case = self.ignore_case(pattern);
+        let cursor_y = self.buf.cursor_y;
+        let cursor_byte = byte_index(&self.buf.content.line(cursor_y), self.buf.cursor_x);
+        let mut total = 0;
+        let mut current: Option<usize> = None;
+        for y in 0..self.buf.content.line_count() {
+            for at in find_all(&self.buf.content.line(y), pattern, ignore_case) {
+                total += 1;
+                if total > MATCH_COUNT_LIMIT {
+                    let current = match current {
+                        Some(current) => current.to_string(),
+                        None => format!(">{}", MATCH_COUNT_LIMIT),
+                    };
+                    return format!("[{}/{}+]", current, MATCH_COUNT_LIMIT);
+                }
+                if (y, at) == (cursor_y, cursor_byte) {
+                    current = Some(total);
+                }
+            }
+        }
+        format!("[{}/{}]", current.unwrap_or(0), total)
+    }
+
+    // With smartcase, only a pattern with capitals in it is case sensitive
+    fn ignore_case(&self, pattern: &str) -> bool {
+        self.smartcase && !pattern.chars().any(char::is_uppercase)
+    }
+
+    // Nearest match of `pattern` before or after the cursor as (x, y, wrapped),
+    // wrapping around the ends of the buffer
+    fn find_match(&self, pattern: &str, forward: bool) -> Option<(usize, usize, bool)> {
+        let line_count = self.buf.content.line_count();
+        let cursor_byte = byte_index(&self.buf.content.line(self.buf.cursor_y), self.buf.cursor_x);
+
+        for step in 0..=line_count {
+            let y = if forward {
+                (self.buf.cursor_y + step) % line_count
+            } else {
+                (self.buf.cursor_y + line_count - step % line_count) % line_count
+            };
+            let line = &self.buf.content.line(y);
+            let matches = find_all(line, pattern, self.ignore_case(pattern));
+
+            // The cursor line is searched in two halves: past the cursor
+            // first, and the rest only after wrapping all the way around
+            let found = match (step, forward) {
+                (0, true) => matches.into_iter().find(|&i| i > cursor_byte),
+                (0, false) => matches.into_iter().rev().find(|&i| i < cursor_byte),
+                (_, true) => matches.into_iter().next(),
+                (_, false) => matches.into_iter().next_back(),
+            };
+            if let Some(i) = found {
+                let wrapped = if forward {
+                    y < self.buf.cursor_y || step == line_count
+                } else {
+                    y > self.buf.cursor_y || step == line_count
+                };
+                return Some((char_count(&line[..i]), y, wrapped));
+            }
+        }
+        None
+    }
+
+    fn set_status(&mut self, message: impl Into<String>) {
+        self.status_message = Some((message.into(), Instant::now()));
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let Some(path) = self.buf.filename.clone() else {
+            self.prompt_buffer.clear();
+            self.mode = Mode::SaveAs;
+            return Ok(());
+        };
+
+        if self.buf.read_only {
+            self.set_status("'readonly' option is set (add ! to override)");
+            return Ok(());
+        }
+        let trimmed = if self.trim_trailing_whitespace {
+            self.trim_whitespace()
+        } else {
+            0
+        };
+        let text = self.buf.file_text();
+
+        // Write next to the original and rename over it, so a failed write
+        // never leaves the original truncated. A symlink is followed so the
+        // file it points to is replaced rather than the link, and the file
+        // keeps its permissions.
+        let target = fs::canonicalize(&path).unwrap_or_else(|_| path.clone());
+        let permissions = fs::metadata(&target).map(|m| m.permissions()).ok();
+        let file_name = target.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = target.with_file_name(format!(".{}.tmp", file_name));
+        let written = fs::File::create(&tmp_path).and_then(|mut file| {
+            file.write_all(text.as_bytes())?;
+            if let Some(permissions) = permissions {
+                file.set_permissions(permissions)?;
+            }
+            file.sync_all()
+        });
+        if let Err(e) = written.and_then(|_| fs::rename(&tmp_path, &target)) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        self.buf.dirty = false;
+        self.buf.remove_swap();
+        let _ = recent::record(&path);
+        self.quit_pending = false;
+        let mut message = format!(
+            "\"{}\" written {} lines",
+            path.display(),
+            self.buf.content.line_count()
+        );
+        if trimmed > 0 {
+            message.push_str(&format!(", {} trimmed", trimmed));
+        }
+        self.set_status(message);
+        Ok(())
+    }
+
+    // Strip trailing spaces and tabs from every line, and drop blank lines at
+    // the end so the file ends in exactly one newline. Returns how many lines
+    // were trimmed or dropped.
+    fn trim_whitespace(&mut self) -> usize {
+        let is_space = |c: char| c == ' ' || c == '\t';
+        let lines: Vec<String> = self.buf.content.lines().collect();
+        let mut keep = lines.len();
+        while keep > 1 && lines[keep - 1].trim_matches(is_space).is_empty() {
+            keep -= 1;
+        }
+        let changed: Vec<usize> = (0..keep)
+            .filter(|&y| lines[y].ends_with(is_space))
+            .collect();
+        let dropped = lines.len() - keep;
+        if changed.is_empty() && dropped == 0 {
+            return 0;
+        }
+        if !self.record_undo() {
+            return 0;
+        }
+        for &y in &changed {
+            self.buf
+                .content
+                .set_line(y, lines[y].trim_end_matches(is_space));
+        }
+        if dropped > 0 {
+            self.buf.content.remove_lines(keep..lines.len());
+        }
+        self.buf.cursor_y = self.buf.cursor_y.min(keep - 1);
+        self.clamp_cursor_x();
+        changed.len() + dropped
+    }
+
+    // Saving must never take the editor down, so errors go to the status line
+    fn save_or_report(&mut self) {
+        if let Err(e) = self.save() {
+            self.set_status(format!("Error writing file: {}", e));
+        }
+    }
+
+    fn move_cursor_left(&mut self) {
+        if self.buf.cursor_x > 0 {
+            self.buf.cursor_x -= 1;
+        }
+    }
+
+    fn move_cursor_right(&mut self) {
+        if self.buf.cursor_x < self.max_cursor_x() {
+            self.buf.cursor_x += 1;
+        }
+    }
+
+    // Insert mode can sit after the last char; other modes stay on it
+    fn max_cursor_x(&self) -> usize {
+        let line_len = self.buf.content.line_len(self.buf.cursor_y);
+        if matches!(self.mode, Mode::Insert | Mode::Replace) {
+            line_len
+        } else {
+            line_len.saturating_sub(1)
+        }
+    }
+
+    fn clamp_cursor_x(&mut self) {
+        self.buf.cursor_x = self.buf.cursor_x.min(self.max_cursor_x());
+    }
+
+    // Up and down step over closed folds
+    fn move_cursor_up(&mut self) {
+        if let Some(y) = self.buf.prev_visible(self.buf.cursor_y) {
+            self.move_to_line(y);
+        }
+    }
+
+    fn move_cursor_down(&mut self) {
+        if let Some(y) = self.buf.next_visible(self.buf.cursor_y) {
+            self.move_to_line(y);
+        }
+    }
+
+    // Open the fold on the cursor line, or close the lines below it that are
+    // indented deeper, up to the next one back at its level. Blank lines
+    // only belong to the fold when more of it follows.
+    fn toggle_fold(&mut self) {
+        let y = self.buf.cursor_y;
+        if self.buf.remove_fold(y) {
+            return;
+        }
+        let indent =
+            |line: &str| display_col(line, char_count(leading_whitespace(line)), self.tab_width);
+        let level = indent(&self.buf.content.line(y));
+        let mut end = y + 1;
+        for next in y + 1..self.buf.content.line_count() {
+            let line = self.buf.content.line(next);
+            if line.trim().is_empty() {
+                continue;
+            }
+            if indent(&line) <= level {
+                break;
+            }
+            end = next + 1;
+        }
+        if end == y + 1 {
+            self.set_status("No fold found");
+            return;
+        }
+        self.buf.folds.push(y..end);
+    }
+
+    // Note where a jump starts, for the backtick mark and Ctrl-O to come back
+    // to. Jumping after going back with Ctrl-O forgets the newer positions.
+    fn remember_jump(&mut self) {
+        let position = (self.buf.cursor_x, self.buf.cursor_y);
+        self.buf.marks.insert('`', position);
+        let buf = &mut self.buf;
+        buf.jump_list.truncate(buf.jump_index);
+        if buf.jump_list.last() != Some(&position) {
+            if buf.jump_list.len() == JUMP_LIMIT {
+                buf.jump_list.remove(0);
+            }
+            buf.jump_list.push(position);
+        }
+        buf.jump_index = buf.jump_list.len();
+    }
+
+    // Ctrl-O and Ctrl-I: step back or forward through the jump list. The
+    // first step back saves where the cursor is, so Ctrl-I can return there.
+    fn walk_jumps(&mut self, count: usize, back: bool) {
+        let buf = &mut self.buf;
+        let target = if back {
+            if buf.jump_index == buf.jump_list.len() {
+                let position = (buf.cursor_x, buf.cursor_y);
+                if buf.jump_list.last() != Some(&position) {
+                    buf.jump_list.push(position);
+                }
+                buf.jump_index = buf.jump_list.len() - 1;
+            }
+            buf.jump_index.checked_sub(count)
+        } else {
+            Some(buf.jump_index + count).filter(|&i| i < buf.jump_list.len())
+        };
+        let Some(index) = target else {
+            return;
+        };
+        buf.jump_index = index;
+        // Lines may have gone since the position was saved
+        let (x, y) = buf.jump_list[index];
+        buf.cursor_y = y.min(buf.content.line_count() - 1);
+        buf.cursor_x = x;
+        self.clamp_cursor_x();
+    }
+
+    // Jump to mark `name`, or to the first non-blank of its line if
+    // `linewise`; lines deleted since it was set pull it back into the buffer
+    fn goto_mark(&mut self, name: char, linewise: bool) {
+        let name = if name == '\'' { '`' } else { name };
+        let Some(&(x, y)) = self.buf.marks.get(&name) else {
+            self.set_status("Mark not set");
+            return;
+        };
+        self.remember_jump();
+        self.buf.cursor_y = y.min(self.buf.content.line_count() - 1);
+        let line = self.buf.content.line(self.buf.cursor_y);
+        self.buf.cursor_x = if linewise {
+            first_non_blank(&line)
+        } else {
+            x.min(char_count(&line))
+        };
+    }
+
+    // Jump to a 1-based line number, clamped to the buffer
+    fn goto_line(&mut self, number: usize) {
+        let y = number.clamp(1, self.buf.content.line_count()) - 1;
+        self.move_to_line(y);
+    }
+
+    // Like `goto_line` but lands on the first non-blank, as `:N` does
+    fn jump_to_line(&mut self, number: usize) {
+        self.goto_line(number);
+        self.buf.cursor_x = first_non_blank(&self.buf.content.line(self.buf.cursor_y));
+        self.keep_preferred_col = false;
+    }
+
+    // Move to line `y`, as close to `preferred_col` as the line allows
+    fn move_to_line(&mut self, y: usize) {
+        self.buf.cursor_y = y;
+        let line = self.buf.content.line(y);
+        self.buf.cursor_x = char_at_col(&line, self.buf.preferred_col, self.tab_width);
+        self.clamp_cursor_x();
+        self.keep_preferred_col = true;
+    }
+
+    // `gj` and `gk`: move by screen rows, which are lines unless wrapping
+    fn move_row_down(&mut self) {
+        self.move_by_row(true);
+    }
+
+    fn move_row_up(&mut self) {
+        self.move_by_row(false);
+    }
+
+    // Move to the row below or above the cursor's, keeping its column on
+    // the row, and only going to another line from its first or last row
+    fn move_by_row(&mut self, forward: bool) {
+        let y = self.buf.cursor_y;
+        if !self.wrap || self.buf.fold_at(y).is_some() {
+            if forward {
+                self.move_cursor_down();
+            } else {
+                self.move_cursor_up();
+            }
+            return;
+        }
+        let width = self.text_area().0;
+        let rows = |y: usize| {
+            let line = self.buf.content.line(y);
+            let starts = wrap_starts(&line, width, self.tab_width);
+            (line, starts)
+        };
+        let (line, starts) = rows(y);
+        let row = starts
+            .iter()
+            .rposition(|&(i, _)| i <= self.buf.cursor_x)
+            .unwrap_or(0);
+        let col = display_col(&line, self.buf.cursor_x, self.tab_width) - starts[row].1;
+
+        let (y, row) = if forward && row + 1 < starts.len() {
+            (y, row + 1)
+        } else if !forward && row > 0 {
+            (y, row - 1)
+        } else if forward {
+            match self.buf.next_visible(y) {
+                Some(next) => (next, 0),
+                None => return,
+            }
+        } else {
+            match self.buf.prev_visible(y) {
+                Some(prev) => (prev, self.rows_of_line(prev, width) - 1),
+                None => return,
+            }
+        };
+        self.buf.cursor_y = y;
+        if self.buf.fold_at(y).is_some() {
+            self.buf.cursor_x = 0;
+            return;
+        }
+        let (line, starts) = rows(y);
+        // Short of the end of the row, which is where the next one starts
+        let row_end = starts.get(row + 1).map(|&(i, _)| i - 1);
+        let x = char_at_col(&line, starts[row].1 + col, self.tab_width);
+        self.buf.cursor_x = row_end.map_or(x, |end| x.min(end));
+        self.clamp_cursor_x();
+    }
+
+    fn move_word_forward(&mut self) {
+        (self.buf.cursor_x, self.buf.cursor_y) =
+            self.next_word_start(self.buf.cursor_x, self.buf.cursor_y);
+    }
+
+    fn move_word_backward(&mut self) {
+        (self.buf.cursor_x, self.buf.cursor_y) =
+            self.prev_word_start(self.buf.cursor_x, self.buf.cursor_y);
+    }
+
+    fn move_paragraph_forward(&mut self) {
+        (self.buf.cursor_x, self.buf.cursor_y) = self.next_paragraph(self.buf.cursor_y);
+    }
+
+    fn move_paragraph_backward(&mut self) {
+        (self.buf.cursor_x, self.buf.cursor_y) = self.prev_paragraph(self.buf.cursor_y);
+    }
+
+    // The next blank line below `y` that follows some text, or the end of the
+    // buffer; a run of blank lines counts as one boundary
+    fn next_paragraph(&self, y: usize) -> (usize, usize) {
+        let last = self.buf.content.line_count() - 1;
+        let is_blank = |y: usize| self.buf.content.line(y).trim().is_empty();
+        let mut y = y;
+        while y < last && is_blank(y) {
+            y += 1;
+        }
+        while y < last && !is_blank(y) {
+            y += 1;
+        }
+        if is_blank(y) {
+            (0, y)
+        } else {
+            (self.buf.content.line_len(y), y)
+        }
+    }
+
+    // Like `next_paragraph` but upwards, stopping at the start of the buffer
+    fn prev_paragraph(&self, y: usize) -> (usize, usize) {
+        let is_blank = |y: usize| self.buf.content.line(y).trim().is_empty();
+        let mut y = y;
+        while y > 0 && is_blank(y) {
+            y -= 1;
+        }
+        while y > 0 && !is_blank(y) {
+            y -= 1;
+        }
+        (0, y)
+    }
+
+    // `d}`, `c{`, `y}` and so on: run `operator` from the cursor to the
+    // `count`th paragraph boundary in that direction. Like Vim's exclusive
+    // motions, an end at the start of a line stops at the end of the line
+    // before, so the blank line itself is kept, and then covers whole lines
+    // if it starts at or before the first non-blank.
+    fn operate_on_paragraphs(&mut self, operator: Pending, forward: bool, count: usize) {
+        let cursor = (self.buf.cursor_x, self.buf.cursor_y);
+        let mut target = cursor;
+        for _ in 0..count {
+            target = if forward {
+                self.next_paragraph(target.1)
+            } else {
+                self.prev_paragraph(target.1)
+            };
+        }
+        let (start, mut end) = if forward {
+            (cursor, target)
+        } else {
+            (target, cursor)
+        };
+        let mut linewise = false;
+        if end.0 == 0 && end.1 > start.1 {
+            end = (self.buf.content.line_len(end.1 - 1), end.1 - 1);
+            linewise = start.0 <= first_non_blank(&self.buf.content.line(start.1));
+        }
+
+        if linewise {
+            let lines = end.1 - start.1 + 1;
+            self.buf.cursor_y = start.1;
+            match operator {
+                Pending::Change => self.change_lines(lines),
+                Pending::Yank => {
+                    self.yank_lines(lines);
+                    self.buf.cursor_x = start.0;
+                }
+                _ => {
+                    self.delete_lines(lines);
+                    self.buf.cursor_x = first_non_blank(&self.buf.content.line(self.buf.cursor_y));
+                }
+            }
+            return;
+        }
+        if start == end {
+            if operator == Pending::Change {
+                self.type_replacement(false);
+            }
+            return;
+        }
+        if operator == Pending::Yank {
+            let text = self.range_text(start, end);
+            self.set_register(Register::Chars(text));
+            (self.buf.cursor_x, self.buf.cursor_y) = start;
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+        let removed = self.remove_range(start, end);
+        self.set_register(Register::Chars(removed));
+        (self.buf.cursor_x, self.buf.cursor_y) = start;
+        self.buf.dirty = true;
+        if operator == Pending::Change {
+            self.type_replacement(true);
+        }
+    }
+
+    // Start of the word after (x, y); an empty line counts as a word, and the
+    // last char of the buffer is as far as it goes
+    fn next_word_start(&self, x: usize, y: usize) -> (usize, usize) {
+        let (mut x, mut y) = (x, y);
+        let mut chars: Vec<char> = self.buf.content.line(y).chars().collect();
+
+        if let Some(&c) = chars.get(x) {
+            let class = char_class(c);
+            if class != CharClass::Whitespace {
+                while x < chars.len() && char_class(chars[x]) == class {
+                    x += 1;
+                }
+            }
+        }
+
+        loop {
+            while x < chars.len() && char_class(chars[x]) == CharClass::Whitespace {
+                x += 1;
+            }
+            if x < chars.len() {
+                return (x, y);
+            }
+            if y + 1 == self.buf.content.line_count() {
+                return (chars.len().saturating_sub(1), y);
+            }
+            y += 1;
+            x = 0;
+            chars = self.buf.content.line(y).chars().collect();
+            if chars.is_empty() {
+                return (0, y);
+            }
+        }
+    }
+
+    // The bracket pairing with the one under the cursor, counting nested pairs
+    fn find_matching_bracket(&self) -> Option<(usize, usize)> {
+        self.match_bracket_within(0..self.buf.content.line_count())
+    }
+
+    // Like `find_matching_bracket`, but only looking at the lines in `lines`
+    fn match_bracket_within(&self, lines: Range<usize>) -> Option<(usize, usize)> {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        let c = self.buf.content.line(y).chars().nth(x)?;
+        let (partner, forward) = match c {
+            '(' => (')', true),
+            '[' => (']', true),
+            '{' => ('}', true),
+            ')' => ('(', false),
+            ']' => ('[', false),
+            '}' => ('{', false),
+            _ => return None,
+        };
+
+        let (mut x, mut y) = (x, y);
+        let mut chars: Vec<char> = self.buf.content.line(y).chars().collect();
+        let mut depth = 0;
+        loop {
+            // Step to the next char in the direction of the search
+            if forward {
+                x += 1;
+                while x >= chars.len() {
+                    y += 1;
+                    if y >= lines.end {
+                        return None;
+                    }
+                    chars = self.buf.content.line(y).chars().collect();
+                    x = 0;
+                }
+            } else {
+                while x == 0 {
+                    if y <= lines.start {
+                        return None;
+                    }
+                    y -= 1;
+                    chars = self.buf.content.line(y).chars().collect();
+                    x = chars.len();
+                }
+                x -= 1;
+            }
+
+            if chars[x] == c {
+                depth += 1;
+            } else if chars[x] == partner {
+                if depth == 0 {
+                    return Some((x, y));
+                }
+                depth -= 1;
+            }
+        }
+    }
+
+    // Start of the word before (x, y), or the start of the buffer
+    fn prev_word_start(&self, x: usize, y: usize) -> (usize, usize) {
+        let (mut x, mut y) = (x, y);
+        let mut chars: Vec<char> = self.buf.content.line(y).chars().collect();
+        x = x.min(chars.len());
+
+        loop {
+            if x == 0 {
+                if y == 0 {
+                    return (0, 0);
+                }
+                y -= 1;
+                chars = self.buf.content.line(y).chars().collect();
+                x = chars.len();
+                if chars.is_empty() {
+                    return (0, y);
+                }
+                continue;
+            }
+            if char_class(chars[x - 1]) != CharClass::Whitespace {
+                break;
+            }
+            x -= 1;
+        }
+
+        let class = char_class(chars[x - 1]);
+        while x > 0 && char_class(chars[x - 1]) == class {
+            x -= 1;
+        }
+        (x, y)
+    }
+
+    fn snapshot(&self) -> EditorSnapshot {
+        EditorSnapshot {
+            content: self.buf.content.snapshot(),
+            cursor_x: self.buf.cursor_x,
+            cursor_y: self.buf.cursor_y,
+            row_offset: self.buf.row_offset,
+            col_offset: self.buf.col_offset,
+        }
+    }
+
+    fn restore(&mut self, snapshot: EditorSnapshot) {
+        self.buf.content.replace(snapshot.content);
+        self.buf.cursor_x = snapshot.cursor_x;
+        self.buf.cursor_y = snapshot.cursor_y;
+        self.buf.row_offset = snapshot.row_offset;
+        self.buf.col_offset = snapshot.col_offset;
+        self.clamp_cursor();
+        self.scroll();
+        self.buf.dirty = true;
+    }
+
+    // Pull the cursor back inside the buffer after edits that remove text
+    fn clamp_cursor(&mut self) {
+        self.buf.cursor_y = self.buf.cursor_y.min(self.buf.content.line_count() - 1);
+        self.buf.cursor_x = self
+            .buf
+            .cursor_x
+            .min(self.buf.content.line_len(self.buf.cursor_y));
+    }
+
+    // Call before every mutation of `content`; returns false, and the edit
+    // shouldn't go ahead, when the buffer is read-only
+    #[must_use]
+    fn record_undo(&mut self) -> bool {
+        if self.buf.read_only {
+            self.set_status("Buffer is read-only");
+            return false;
+        }
+        if self.buf.undo_stack.len() == UNDO_LIMIT {
+            self.buf.undo_stack.remove(0);
+        }
+        self.buf.undo_stack.push(self.snapshot());
+        self.buf.redo_stack.clear();
+        self.edits += 1;
+        self.buf.insert_group_end = None;
+        true
+    }
+
+    fn undo(&mut self) {
+        if self.buf.read_only {
+            self.set_status("Buffer is read-only");
+            return;
+        }
+        match self.buf.undo_stack.pop() {
+            Some(snapshot) => {
+                self.buf.redo_stack.push(self.snapshot());
+                self.restore(snapshot);
+            }
+            None => self.set_status("Already at oldest change"),
+        }
+        self.buf.insert_group_end = None;
+    }
+
+    fn redo(&mut self) {
+        if self.buf.read_only {
+            self.set_status("Buffer is read-only");
+            return;
+        }
+        match self.buf.redo_stack.pop() {
+            Some(snapshot) => {
+                self.buf.undo_stack.push(self.snapshot());
+                self.restore(snapshot);
+            }
+            None => self.set_status("Already at newest change"),
+        }
+        self.buf.insert_group_end = None;
+    }
+
+    fn insert_char(&mut self, c: char) {
+        if self.buf.insert_group_end != Some((self.buf.cursor_x, self.buf.cursor_y))
+            && !self.record_undo()
+        {
+            return;
+        }
+        self.buf
+            .content
+            .insert_char(self.buf.cursor_y, self.buf.cursor_x, c);
+        self.buf.cursor_x += 1;
+        self.buf.dirty = true;
+        self.buf.insert_group_end = Some((self.buf.cursor_x, self.buf.cursor_y));
+    }
+
+    // Ctrl-N and Ctrl-P: replace the word before the cursor with the next or
+    // previous word in the buffer starting with it, coming back round to what
+    // was typed
+    fn complete(&mut self, completion: Option<Completion>, forward: bool) {
+        let cursor = (self.buf.cursor_x, self.buf.cursor_y);
+        let completion = match completion.filter(|c| c.end == cursor) {
+            Some(completion) => completion,
+            None => {
+                let line = self.buf.content.line(cursor.1);
+                let chars: Vec<char> = line.chars().take(cursor.0).collect();
+                let start = chars
+                    .iter()
+                    .rposition(|&c| char_class(c) != CharClass::Word)
+                    .map_or(0, |i| i + 1);
+                let prefix: String = chars[start..].iter().collect();
+                let candidates = self.completions(&prefix, (start, cursor.1));
+                if candidates.is_empty() {
+                    self.set_status("Pattern not found");
+                    return;
+                }
+                Completion {
+                    start: (start, cursor.1),
+                    prefix,
+                    candidates,
+                    index: None,
+                    end: cursor,
+                }
+            }
+        };
+        let last = completion.candidates.len() - 1;
+        let index = match (completion.index, forward) {
+            (None, true) => Some(0),
+            (None, false) => Some(last),
+            (Some(i), true) => (i < last).then_some(i + 1),
+            (Some(i), false) => i.checked_sub(1),
+        };
+        let word = match index {
+            Some(i) => &completion.candidates[i],
+            None => &completion.prefix,
+        };
+
+        if self.buf.insert_group_end != Some(cursor) && !self.record_undo() {
+            return;
+        }
+        self.buf.content.remove(completion.start, cursor);
+        let end = self.insert_text(completion.start.0, completion.start.1, word);
+        (self.buf.cursor_x, self.buf.cursor_y) = end;
+        self.buf.insert_group_end = Some(end);
+        self.buf.dirty = true;
+        match index {
+            Some(i) => self.set_status(format!("match {} of {}", i + 1, last + 1)),
+            None => self.set_status("Back at original"),
+        }
+        self.completion = Some(Completion {
+            index,
+            end,
+            ..completion
+        });
+    }
+
+    // Words in the buffer longer than `prefix` and starting with it, each
+    // once, in the order they come after the word at `at`
+    fn completions(&self, prefix: &str, at: (usize, usize)) -> Vec<String> {
+        let line_count = self.buf.content.line_count();
+        let mut seen = HashSet::new();
+        let mut candidates = Vec::new();
+        // The cursor line comes first and last: the words after the one being
+        // completed, and after wrapping around, the ones before it
+        for (step, y) in (at.1..line_count).chain(0..=at.1).enumerate() {
+            let chars: Vec<char> = self.buf.content.line(y).chars().collect();
+            let mut x = 0;
+            while x < chars.len() {
+                let len = chars[x..]
+                    .iter()
+                    .take_while(|&&c| char_class(c) == CharClass::Word)
+                    .count();
+                if len == 0 {
+                    x += 1;
+                    continue;
+                }
+                let wanted = match (y == at.1, step) {
+                    (false, _) => true,
+                    (true, 0) => x > at.0,
+                    (true, _) => x < at.0,
+                };
+                let word: String = chars[x..x + len].iter().collect();
+                if wanted
+                    && word.len() > prefix.len()
+                    && word.starts_with(prefix)
+                    && seen.insert(word.clone())
+                {
+                    candidates.push(word);
+                }
+                x += len;
+            }
+        }
+        candidates
+    }
+
+    fn insert_tab(&mut self) {
+        if !self.expand_tabs {
+            self.insert_char('\t');
+            return;
+        }
+        let col = display_col(
+            &self.buf.content.line(self.buf.cursor_y),
+            self.buf.cursor_x,
+            self.tab_width,
+        );
+        for _ in 0..self.tab_width - col % self.tab_width {
+            self.insert_char(' ');
+        }
+    }
+
+    fn delete_char(&mut self) {
+        if self.buf.cursor_x == 0 && self.buf.cursor_y == 0 {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        let line = self.buf.content.line(y);
+        if x > 0 && self.expand_tabs && self.smarttab && line.chars().take(x).all(|c| c == ' ') {
+            // Back to the previous tab stop
+            let stop = (x - 1) / self.tab_width * self.tab_width;
+            self.buf.content.remove((stop, y), (x, y));
+            self.buf.cursor_x = stop;
+        } else if x > 0 {
+            self.buf.content.remove((x - 1, y), (x, y));
+            self.buf.cursor_x -= 1;
+        } else {
+            // Join with the previous line
+            let prev_len = self.buf.content.line_len(y - 1);
+            self.buf.content.remove((prev_len, y - 1), (0, y));
+            self.buf.cursor_y -= 1;
+            self.buf.cursor_x = prev_len;
+        }
+        self.buf.dirty = true;
+    }
+
+    // Delete from column `x` up to the cursor on the cursor line
+    fn delete_back_to(&mut self, x: usize) {
+        let (cursor_x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        if x >= cursor_x {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+        self.buf.content.remove((x, y), (cursor_x, y));
+        self.buf.cursor_x = x;
+        self.buf.dirty = true;
+    }
+
+    fn delete_char_under_cursor(&mut self, count: usize) {
+        let line_len = self.buf.content.line_len(self.buf.cursor_y);
+        if self.buf.cursor_x >= line_len {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+
+        let end = self.buf.cursor_x.saturating_add(count).min(line_len);
+        let start = (self.buf.cursor_x, self.buf.cursor_y);
+        let removed = self.remove_range(start, (end, self.buf.cursor_y));
+        self.set_register(Register::Chars(removed));
+        // Like Vim, deleting the last char leaves the cursor on the new last char
+        let new_len = line_len - (end - self.buf.cursor_x);
+        if self.buf.cursor_x >= new_len && self.buf.cursor_x > 0 {
+            self.buf.cursor_x = new_len.saturating_sub(1);
+        }
+        self.buf.dirty = true;
+    }
+
+    // Returns whether there was anything to delete
+    fn delete_to_end_of_line(&mut self) -> bool {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        let line_len = self.buf.content.line_len(y);
+        if x >= line_len {
+            return false;
+        }
+        if !self.record_undo() {
+            return false;
+        }
+        let removed = self.remove_range((x, y), (line_len, y));
+        self.set_register(Register::Chars(removed));
+        self.buf.dirty = true;
+        true
+    }
+
+    // Start Insert mode to type what replaces a deletion. The typing joins
+    // the deletion's undo step, or gets one of its own when nothing was
+    // `deleted`.
+    fn type_replacement(&mut self, deleted: bool) {
+        self.buf.insert_group_end = deleted.then_some((self.buf.cursor_x, self.buf.cursor_y));
+        self.mode = Mode::Insert;
+    }
+
+    fn delete_to_start_of_line(&mut self) {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        if x == 0 {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+        let removed = self.remove_range((0, y), (x, y));
+        self.set_register(Register::Chars(removed));
+        self.buf.cursor_x = 0;
+        self.buf.dirty = true;
+    }
+
+    // `dG` and `dgg`: delete the lines from the cursor through line `number`,
+    // counted from 1
+    fn delete_to_line(&mut self, number: usize) {
+        let last = self.buf.content.line_count() - 1;
+        let target = number.saturating_sub(1).min(last);
+        let start = self.buf.cursor_y.min(target);
+        let end = self.buf.cursor_y.max(target);
+        self.buf.cursor_y = start;
+        self.delete_lines(end - start + 1);
+        self.buf.cursor_x = first_non_blank(&self.buf.content.line(self.buf.cursor_y));
+    }
+
+    // Delete up to the start of the `count`th next word, stopping at the end
+    // of the line rather than joining the next one; returns whether there was
+    // anything to delete
+    fn delete_words(&mut self, count: usize) -> bool {
+        let line_len = self.buf.content.line_len(self.buf.cursor_y);
+        if self.buf.cursor_x >= line_len {
+            return false;
+        }
+
+        let mut end = self.buf.cursor_x;
+        for _ in 0..count {
+            let (x, y) = self.next_word_start(end, self.buf.cursor_y);
+            // At the end of the buffer it stays put on the last char
+            if y != self.buf.cursor_y || x <= end {
+                end = line_len;
+                break;
+            }
+            end = x;
+        }
+
+        if !self.record_undo() {
+            return false;
+        }
+        let start = (self.buf.cursor_x, self.buf.cursor_y);
+        let removed = self.remove_range(start, (end, self.buf.cursor_y));
+        self.set_register(Register::Chars(removed));
+        self.buf.dirty = true;
+        true
+    }
+
+    // Delete the inside of the text object around the cursor delimited by
+    // `delim`, then start typing in its place if `change` is set
+    fn delete_inner(&mut self, delim: char, change: bool) {
+        let Some(range) = self.inner_object(delim) else {
+            return;
+        };
+        let y = self.buf.cursor_y;
+        if !range.is_empty() {
+            if !self.record_undo() {
+                return;
+            }
+            let removed = self.remove_range((range.start, y), (range.end, y));
+            self.set_register(Register::Chars(removed));
+            self.buf.dirty = true;
+        }
+        self.buf.cursor_x = range.start;
+        if change {
+            self.type_replacement(!range.is_empty());
+        }
+    }
+
+    // Chars between the quotes or brackets named by `delim` that surround the
+    // cursor on its line. For quotes, a pair after the cursor counts too.
+    fn inner_object(&self, delim: char) -> Option<Range<usize>> {
+        let chars: Vec<char> = self.buf.content.line(self.buf.cursor_y).chars().collect();
+        let x = self.buf.cursor_x;
+        let (open, close) = match delim {
+            '"' | '\'' | '`' => {
+                // Quotes pair up in order from the start of the line
+                let quotes: Vec<usize> = (0..chars.len()).filter(|&i| chars[i] == delim).collect();
+                let pair = quotes.chunks_exact(2).find(|pair| pair[1] >= x)?;
+                return Some(pair[0] + 1..pair[1]);
+            }
+            '(' | ')' | 'b' => ('(', ')'),
+            '[' | ']' => ('[', ']'),
+            '{' | '}' | 'B' => ('{', '}'),
+            '<' | '>' => ('<', '>'),
+            _ => return None,
+        };
+
+        // Innermost unclosed opening bracket at or before the cursor
+        let mut depth = 0;
+        let mut start = None;
+        for i in (0..=x.min(chars.len().checked_sub(1)?)).rev() {
+            if chars[i] == close && i != x {
+                depth += 1;
+            } else if chars[i] == open {
+                if depth == 0 {
+                    start = Some(i);
+                    break;
+                }
+                depth -= 1;
+            }
+        }
+        let start = start?;
+
+        let mut depth = 0;
+        for (i, &c) in chars.iter().enumerate().skip(start + 1) {
+            if c == open {
+                depth += 1;
+            } else if c == close {
+                if depth == 0 {
+                    return Some(start + 1..i);
+                }
+                depth -= 1;
+            }
+        }
+        None
+    }
+
+    fn delete_lines(&mut self, count: usize) {
+        if !self.record_undo() {
+            return;
+        }
+        let end = self
+            .buf
+            .cursor_y
+            .saturating_add(count)
+            .min(self.buf.content.line_count());
+        let removed = self.buf.content.remove_lines(self.buf.cursor_y..end);
+        self.set_register(Register::Lines(removed));
+        self.clamp_cursor();
+        self.buf.dirty = true;
+    }
+
+    // `cc` and `S`: replace `count` lines with one empty line, keeping the
+    // first one's indentation with auto-indent, and start typing on it
+    fn change_lines(&mut self, count: usize) {
+        if !self.record_undo() {
+            return;
+        }
+        let y = self.buf.cursor_y;
+        let end = y.saturating_add(count).min(self.buf.content.line_count());
+        let removed: Vec<String> = (y..end).map(|y| self.buf.content.line(y)).collect();
+        let indent = if self.auto_indent {
+            leading_whitespace(&removed[0]).to_string()
+        } else {
+            String::new()
+        };
+        self.buf.content.remove_lines(y + 1..end);
+        self.buf.content.set_line(y, &indent);
+        self.set_register(Register::Lines(removed));
+        self.buf.cursor_x = char_count(&indent);
+        self.buf.dirty = true;
+        self.type_replacement(true);
+    }
+
+    fn yank_lines(&mut self, count: usize) {
+        let end = self
+            .buf
+            .cursor_y
+            .saturating_add(count)
+            .min(self.buf.content.line_count());
+        let lines: Vec<String> = (self.buf.cursor_y..end)
+            .map(|y| self.buf.content.line(y))
+            .collect();
+        self.set_status(match lines.len() {
+            1 => "1 line yanked".to_string(),
+            n => format!("{} lines yanked", n),
+        });
+        self.set_register(Register::Lines(lines));
+    }
+
+    // Store yanked or deleted text, copying it to the system clipboard as well
+    // after `"+`
+    fn set_register(&mut self, register: Register) {
+        if self.use_clipboard {
+            let text = match &register {
+                Register::Lines(lines) => lines.join("\n") + "\n",
+                Register::Chars(text) => text.clone(),
+            };
+            if let Err(e) = self.clipboard.set_text(text) {
+                self.set_status(format!("Clipboard unavailable: {}", e));
+            }
+        }
+        self.register = register;
+    }
+
+    // The system clipboard's text as a register; text ending in a newline
+    // pastes as whole lines
+    fn clipboard_register(&mut self) -> Option<Register> {
+        match self.clipboard.get_text() {
+            Ok(text) => {
+                let text = text.replace("\r\n", "\n");
+                Some(match text.strip_suffix('\n') {
+                    Some(lines) => Register::Lines(lines.split('\n').map(String::from).collect()),
+                    None => Register::Chars(text),
+                })
+            }
+            Err(e) => {
+                self.set_status(format!("Clipboard unavailable: {}", e));
+                None
+            }
+        }
+    }
+
+    // Paste the register `count` times after or before the cursor
+    fn paste(&mut self, after: bool, count: usize) {
+        let register = if self.use_clipboard {
+            match self.clipboard_register() {
+                Some(register) => register,
+                None => return,
+            }
+        } else {
+            self.register.clone()
+        };
+        let size = match &register {
+            Register::Lines(lines) => lines.len(),
+            Register::Chars(text) => text.len(),
+        };
+        if size
+            .checked_mul(count)
+            .is_none_or(|total| total > PASTE_LIMIT)
+        {
+            self.set_status("Count too large to paste");
+            return;
+        }
+        match register {
+            Register::Lines(lines) => {
+                if lines.is_empty() {
+                    return;
+                }
+                if !self.record_undo() {
+                    return;
+                }
+                let y = if after {
+                    self.buf.cursor_y + 1
+                } else {
+                    self.buf.cursor_y
+                };
+                let pasted: Vec<String> = lines
+                    .iter()
+                    .cycle()
+                    .take(lines.len() * count)
+                    .cloned()
+                    .collect();
+                self.buf.content.insert_lines(y, &pasted);
+                self.buf.cursor_y = y;
+                self.buf.cursor_x = first_non_blank(&self.buf.content.line(y));
+            }
+            Register::Chars(text) => {
+                if text.is_empty() {
+                    return;
+                }
+                if !self.record_undo() {
+                    return;
+                }
+                let line_len = self.buf.content.line_len(self.buf.cursor_y);
+                let x = if after {
+                    (self.buf.cursor_x + 1).min(line_len)
+                } else {
+                    self.buf.cursor_x
+                };
+                let (end_x, end_y) = self.insert_text(x, self.buf.cursor_y, &text.repeat(count));
+                // Vim leaves the cursor on the last pasted char of a single-line
+                // paste, and at the start of a multi-line one
+                if end_y == self.buf.cursor_y {
+                    self.buf.cursor_x = end_x.saturating_sub(1);
+                } else {
+                    self.buf.cursor_x = x;
+                }
+            }
+        }
+        self.buf.dirty = true;
+    }
+
+    // Text pasted into the terminal goes in as it is, without the auto-indent
+    // and list continuation typing it would get, and is undone in one go
+    fn handle_paste(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        match self.mode {
+            Mode::Command | Mode::Search => self
+                .command_buffer
+                .push_str(text.lines().next().unwrap_or("")),
+            Mode::SaveAs => self
+                .prompt_buffer
+                .push_str(text.lines().next().unwrap_or("")),
+            _ if text.is_empty() => (),
+            Mode::Insert | Mode::Replace => {
+                if !self.record_undo() {
+                    return;
+                }
+                let (x, y) = self.insert_text(self.buf.cursor_x, self.buf.cursor_y, &text);
+                (self.buf.cursor_x, self.buf.cursor_y) = (x, y);
+                self.buf.insert_group_end = None;
+                // Backspace can't give back chars from before the paste
+                self.replaced.clear();
+                self.buf.dirty = true;
+            }
+            Mode::Normal => {
+                // Like `P` with the text in a register
+                if !self.record_undo() {
+                    return;
+                }
+                let (x, y) = self.insert_text(self.buf.cursor_x, self.buf.cursor_y, &text);
+                (self.buf.cursor_x, self.buf.cursor_y) = (x.saturating_sub(1), y);
+                self.buf.dirty = true;
+            }
+            _ => (),
+        }
+    }
+
+    // Insert `text`, which may contain newlines, at (x, y); returns the
+    // position just past the inserted text
+    fn insert_text(&mut self, x: usize, y: usize, text: &str) -> (usize, usize) {
+        self.buf.content.insert(y, x, text);
+        match text.rsplit_once('\n') {
+            Some((_, last)) => (char_count(last), y + text.matches('\n').count()),
+            None => (x + char_count(text), y),
+        }
+    }
+
+    // The Visual selection as a half-open range; selecting the end of a line
+    // takes its newline along, except at the end of the buffer
+    fn selection_range(&self) -> ((usize, usize), (usize, usize)) {
+        let (start, (end_x, end_y)) = self.selection();
+        let end = if end_x < self.buf.content.line_len(end_y) {
+            (end_x + 1, end_y)
+        } else if end_y + 1 < self.buf.content.line_count() {
+            (0, end_y + 1)
+        } else {
+            (self.buf.content.line_len(end_y), end_y)
+        };
+        (start, end)
+    }
+
+    fn enter_visual(&mut self, mode: Mode) {
+        self.sel_x = self.buf.cursor_x;
+        self.sel_y = self.buf.cursor_y;
+        self.mode = mode;
+    }
+
+    fn current_selection(&self) -> Selection {
+        Selection {
+            mode: self.mode,
+            anchor: (self.sel_x, self.sel_y),
+            cursor: (self.buf.cursor_x, self.buf.cursor_y),
+        }
+    }
+
+    // `gv`: select the last Visual selection again; in Visual mode the
+    // current one takes its place
+    fn reselect(&mut self) {
+        let Some(Selection {
+            mode,
+            anchor: (sel_x, sel_y),
+            cursor: (x, y),
+        }) = self.buf.last_visual
+        else {
+            return;
+        };
+        if self.mode.is_visual() {
+            self.buf.last_visual = Some(self.current_selection());
+        }
+        // Edits since may have removed some of the lines
+        let last = self.buf.content.line_count() - 1;
+        let (sel_y, y) = (sel_y.min(last), y.min(last));
+        self.sel_x = sel_x.min(self.buf.content.line_len(sel_y));
+        self.sel_y = sel_y;
+        (self.buf.cursor_x, self.buf.cursor_y) = (x.min(self.buf.content.line_len(y)), y);
+        self.mode = mode;
+    }
+
+    // First line and line count of a line-wise selection
+    fn selected_lines(&self) -> (usize, usize) {
+        let ((_, start_y), (_, end_y)) = self.selection();
+        (start_y, end_y - start_y + 1)
+    }
+
+    fn delete_selection(&mut self) {
+        if self.mode == Mode::VisualBlock {
+            if !self.record_undo() {
+                return;
+            }
+            let removed = self.take_block(true);
+            self.set_register(Register::Chars(removed));
+            self.buf.dirty = true;
+            self.mode = Mode::Normal;
+            return;
+        }
+        if self.mode == Mode::VisualLine {
+            let (y, count) = self.selected_lines();
+            self.buf.cursor_y = y;
+            self.delete_lines(count);
+            self.mode = Mode::Normal;
+            return;
+        }
+
+        let (start, end) = self.selection_range();
+        if !self.record_undo() {
+            return;
+        }
+        let removed = self.remove_range(start, end);
+        self.set_register(Register::Chars(removed));
+        (self.buf.cursor_x, self.buf.cursor_y) = start;
+        self.clamp_cursor();
+        self.buf.dirty = true;
+        self.mode = Mode::Normal;
+    }
+
+    fn yank_selection(&mut self) {
+        if self.mode == Mode::VisualBlock {
+            let text = self.take_block(false);
+            self.set_register(Register::Chars(text));
+            self.mode = Mode::Normal;
+            return;
+        }
+        if self.mode == Mode::VisualLine {
+            let (y, count) = self.selected_lines();
+            self.buf.cursor_y = y;
+            self.yank_lines(count);
+            self.move_to_line(y);
+            self.mode = Mode::Normal;
+            return;
+        }
+
+        let (start, end) = self.selection_range();
+        self.set_register(Register::Chars(self.range_text(start, end)));
+        (self.buf.cursor_x, self.buf.cursor_y) = start;
+        self.mode = Mode::Normal;
+    }
+
+    // Display columns covered by a Visual Block selection, from the left edge
+    // of one corner to the right edge of the other
+    fn block_cols(&self) -> Range<usize> {
+        let cols = |x: usize, y: usize| {
+            let line = self.buf.content.line(y);
+            let start = display_col(&line, x, self.tab_width);
+            let width = line
+                .chars()
+                .nth(x)
+                .map_or(1, |c| char_width(c, start, self.tab_width));
+            start..start + width.max(1)
+        };
+        let anchor = cols(self.sel_x, self.sel_y);
+        let cursor = cols(self.buf.cursor_x, self.buf.cursor_y);
+        anchor.start.min(cursor.start)..anchor.end.max(cursor.end)
+    }
+
+    // The block's text, one line per selected line, removing it from the
+    // buffer when `remove` is set; the cursor ends up at its top left
+    fn take_block(&mut self, remove: bool) -> String {
+        let (top, lines) = self.selected_lines();
+        let cols = self.block_cols();
+        let mut pieces = Vec::new();
+        for y in top..top + lines {
+            let range = chars_in_cols(&self.buf.content.line(y), cols.clone(), self.tab_width);
+            let (start, end) = ((range.start, y), (range.end, y));
+            pieces.push(if remove {
+                self.remove_range(start, end)
+            } else {
+                self.range_text(start, end)
+            });
+        }
+        self.buf.cursor_y = top;
+        self.buf.cursor_x = char_at_col(&self.buf.content.line(top), cols.start, self.tab_width);
+        self.clamp_cursor_x();
+        pieces.join("\n")
+    }
+
+    // `I` in Visual Block mode: type on the top line, and the text is copied
+    // to the rest of the block when Insert mode ends
+    fn block_insert(&mut self) {
+        let (top, lines) = self.selected_lines();
+        let col = self.block_cols().start;
+        self.buf.cursor_y = top;
+        self.buf.cursor_x = char_at_col(&self.buf.content.line(top), col, self.tab_width);
+        self.pending_block_insert = Some((top + 1..top + lines, col, self.buf.cursor_x));
+        self.mode = Mode::Insert;
+    }
+
+    // Copy what was typed after a block `I` onto the block's other lines,
+    // leaving out lines too short to reach the block
+    fn finish_block_insert(&mut self) {
+        let Some((lines, col, start_x)) = self.pending_block_insert.take() else {
+            return;
+        };
+        // Anything but plain typing on the one line cancels the copy
+        if self.buf.cursor_y + 1 != lines.start || self.buf.cursor_x <= start_x {
+            return;
+        }
+        let y = self.buf.cursor_y;
+        let text = self.range_text((start_x, y), (self.buf.cursor_x, y));
+        for y in lines {
+            let line = self.buf.content.line(y);
+            if display_col(&line, char_count(&line), self.tab_width) < col {
+                continue;
+            }
+            let x = char_at_col(&line, col, self.tab_width);
+            self.buf.content.insert(y, x, &text);
+        }
+    }
+
+    // Text from `start` up to (not including) `end`, as (x, y) positions
+    fn range_text(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        self.buf.content.slice(start, end)
+    }
+
+    // Remove the text from `start` up to (not including) `end` and return it
+    fn remove_range(&mut self, start: (usize, usize), end: (usize, usize)) -> String {
+        let text = self.range_text(start, end);
+        self.buf.content.remove(start, end);
+        text
+    }
+
+    // Overwrite `count` chars from the cursor with `c`, if there are that many
+    fn replace_chars(&mut self, c: char, count: usize) {
+        let line_len = self.buf.content.line_len(self.buf.cursor_y);
+        let end = self.buf.cursor_x.saturating_add(count);
+        if end > line_len {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        self.buf.content.remove((x, y), (end, y));
+        self.buf.content.insert(y, x, &c.to_string().repeat(count));
+        self.buf.cursor_x = end - 1;
+        self.buf.dirty = true;
+    }
+
+    // Add one level of indentation to `count` lines from `y`, or take up to
+    // one level away; empty lines are left alone like in Vim
+    fn shift_lines(&mut self, y: usize, count: usize, indent: bool) {
+        let end = y.saturating_add(count).min(self.buf.content.line_count());
+        let level = if self.expand_tabs {
+            " ".repeat(self.tab_width)
+        } else {
+            "\t".to_string()
+        };
+        let mut changed = false;
+        for line_y in y..end {
+            let line = self.buf.content.line(line_y);
+            let shifted = if indent {
+                if line.is_empty() {
+                    continue;
+                }
+                format!("{}{}", level, line)
+            } else if let Some(rest) = line.strip_prefix('\t') {
+                rest.to_string()
+            } else {
+                let spaces = line.chars().take_while(|&c| c == ' ').count();
+                line.chars().skip(spaces.min(self.tab_width)).collect()
+            };
+            if shifted == line {
+                continue;
+            }
+            if !changed {
+                if !self.record_undo() {
+                    return;
+                }
+                changed = true;
+            }
+            self.buf.content.set_line(line_y, &shifted);
+            // Keep the cursor on the same char of its line
+            if line_y == self.buf.cursor_y {
+                let x = self.buf.cursor_x + char_count(&shifted);
+                self.buf.cursor_x = x.saturating_sub(char_count(&line));
+            }
+        }
+        if changed {
+            self.buf.dirty = true;
+        }
+    }
+
+    // Swap the case of `count` chars from the cursor and move past them, like
+    // Vim's `~`; a char like `ß` may turn into more than one
+    fn toggle_case_under_cursor(&mut self, count: usize) {
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        let line = self.buf.content.line(y);
+        let end = x.saturating_add(count).min(char_count(&line));
+        if x >= end {
+            return;
+        }
+        let toggled: String = line
+            .chars()
+            .skip(x)
+            .take(end - x)
+            .flat_map(|c| -> Vec<char> {
+                if c.is_lowercase() {
+                    c.to_uppercase().collect()
+                } else if c.is_uppercase() {
+                    c.to_lowercase().collect()
+                } else {
+                    vec![c]
+                }
+            })
+            .collect();
+        if self.buf.content.slice((x, y), (end, y)) != toggled {
+            if !self.record_undo() {
+                return;
+            }
+            self.buf.content.remove((x, y), (end, y));
+            self.buf.content.insert(y, x, &toggled);
+            self.buf.dirty = true;
+        }
+        // Normal mode clamps this back onto the last char
+        self.buf.cursor_x = x + char_count(&toggled);
+    }
+
+    // Join the next line onto this one, `count - 1` times like Vim's `3J`,
+    // replacing the joined line's indentation with a single space
+    fn join_lines(&mut self, count: usize) {
+        if self.buf.cursor_y + 1 == self.buf.content.line_count() {
+            return;
+        }
+        if !self.record_undo() {
+            return;
+        }
+
+        let joins = count.saturating_sub(1).max(1);
+        for _ in 0..joins {
+            if self.buf.cursor_y + 1 == self.buf.content.line_count() {
+                break;
+            }
+            let y = self.buf.cursor_y;
+            let line = self.buf.content.line(y);
+            let next = self.buf.content.line(y + 1);
+            let indent = char_count(leading_whitespace(&next));
+            let line_len = char_count(&line);
+            self.buf.content.remove((line_len, y), (indent, y + 1));
+            self.buf.cursor_x = line_len;
+            if indent < char_count(&next) && !line.is_empty() && !line.ends_with([' ', '\t']) {
+                self.buf.content.insert_char(y, line_len, ' ');
+            }
+        }
+        self.buf.dirty = true;
+    }
+
+    // Insert an empty line at index `y` and start typing on it
+    fn open_line(&mut self, y: usize) {
+        if !self.record_undo() {
+            return;
+        }
+        self.buf.content.insert_lines(y, &[String::new()]);
+        self.buf.cursor_y = y;
+        self.buf.cursor_x = 0;
+        self.buf.dirty = true;
+        self.mode = Mode::Insert;
+    }
+
+    fn insert_newline(&mut self) {
+        if !self.record_undo() {
+            return;
+        }
+        let (x, y) = (self.buf.cursor_x, self.buf.cursor_y);
+        let line = self.buf.content.line(y);
+        let mut new_line = line[byte_index(&line, x)..].to_string();
+        let item =
+            list_marker(&line).filter(|(prefix, _)| self.continue_lists && x >= char_count(prefix));
+
+        // Enter on an empty item ends the list instead of adding another
+        if item
+            .as_ref()
+            .is_some_and(|(p, _)| line[p.len()..].trim().is_empty())
+        {
+            self.buf.content.set_line(y, "");
+            self.buf.cursor_x = 0;
+            self.buf.dirty = true;
+            return;
+        }
+
+        // Carry over the indentation before the split, replacing any
+        // whitespace that moved to the new line along with the text
+        let mut indent_len = 0;
+        if let Some((_, next)) = item {
+            indent_len = char_count(&next);
+            new_line = next + new_line.trim_start_matches([' ', '\t']);
+        } else if self.auto_indent {
+            let indent = leading_whitespace(&line[..byte_index(&line, x)]).to_string();
+            indent_len = char_count(&indent);
+            new_line = indent + new_line.trim_start_matches([' ', '\t']);
+        }
+
+        let line_len = char_count(&line);
+        self.buf.content.remove((x, y), (line_len, y));
+        self.buf.content.insert(y, x, &format!("\n{}", new_line));
+        self.buf.cursor_y += 1;
+        self.buf.cursor_x = indent_len;
+        self.buf.dirty = true;
+    }
+}
+
+// Raw mode and the alternate screen for as long as it lives. Dropping it,
+// returning early with an error included, puts the terminal back; a panic
+// does so too before its message is printed, so the message stays readable.
+struct TerminalGuard {
+    // Whether the editor may change the cursor shape, which then needs
+    // undoing
+    cursor_shapes: bool,
+}
+
+impl TerminalGuard {
+    fn enter(cursor_shapes: bool) -> Result<Self> {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            restore_terminal(cursor_shapes);
+            default_hook(info);
+        }));
+        enter_screen()?;
+        Ok(TerminalGuard { cursor_shapes })
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        // The panic hook has already done it, and doing it again after the
+        // message could move the cursor back over it
+        if !std::thread::panicking() {
+            restore_terminal(self.cursor_shapes);
+        }
+    }
+}
+
+// Nothing can be done about errors this late
+fn restore_terminal(cursor_shapes: bool) {
+    if cursor_shapes {
+        let _ = queue!(stdout(), cursor::SetCursorStyle::DefaultUserShape);
+    }
+    let _ = leave_screen();
+}
+
+fn enter_screen() -> Result<()> {
+    terminal::enable_raw_mode()?;
+    execute!(
+        stdout(),
+        terminal::EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableBracketedPaste
+    )
+}
+
+fn leave_screen() -> Result<()> {
+    execute!(
+        stdout(),
+        DisableBracketedPaste,
+        DisableMouseCapture,
+        terminal::LeaveAlternateScreen,
+        cursor::Show
+    )?;
+    terminal::disable_raw_mode()
+}
+
+// Digits typed on the raw terminal up to Enter, echoed as they come; Escape
+// gives up with nothing
+fn read_number() -> Result<String> {
+    let mut typed = String::new();
+    loop {
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        match key.code {
+            KeyCode::Enter => return Ok(typed),
+            KeyCode::Esc => return Ok(String::new()),
+            KeyCode::Backspace if typed.pop().is_some() => print!("\x08 \x08"),
+            KeyCode::Char(c) if c.is_ascii_digit() => {
+                typed.push(c);
+                print!("{}", c);
+            }
+            _ => continue,
+        }
+        stdout().flush()?;
+    }
+}
+
+// Byte offset of the `idx`-th char of `line`, or `line.len()` past the end
+fn byte_index(line: &str, idx: usize) -> usize {
+    line.char_indices().nth(idx).map_or(line.len(), |(i, _)| i)
+}
+
+fn char_class(c: char) -> CharClass {
+    if c.is_whitespace() {
+        CharClass::Whitespace
+    } else if c.is_alphanumeric() || c == '_' {
+        CharClass::Word
+    } else {
+        CharClass::Punctuation
+    }
+}
+
+// Split on `delimiter`, letting a backslash escape it
+fn split_unescaped(text: &str, delimiter: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.peek() == Some(&delimiter) {
+            parts.last_mut().unwrap().push(delimiter);
+            chars.next();
+        } else if c == delimiter {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+    parts
+}
+
+// Byte offsets of every (possibly overlapping) occurrence of `pattern`; a
+// `\<word\>` pattern only matches where the word stands on its own
+fn find_all(line: &str, pattern: &str, ignore_case: bool) -> Vec<usize> {
+    if let Some(word) = whole_word(pattern) {
+        let is_word = |c: Option<char>| c.is_some_and(|c| char_class(c) == CharClass::Word);
+        return find_all(line, word, ignore_case)
+            .into_iter()
+            .filter(|&at| {
+                let end = at
+                    + line[at..]
+                        .chars()
+                        .take(char_count(word))
+                        .map(char::len_utf8)
+                        .sum::<usize>();
+                !is_word(line[..at].chars().next_back()) && !is_word(line[end..].chars().next())
+            })
+            .collect();
+    }
+    if ignore_case {
+        return find_all_ignore_case(line, pattern);
+    }
+    let mut matches = Vec::new();
+    let mut start = 0;
+    while let Some(i) = line[start..].find(pattern) {
+        let at = start + i;
+        matches.push(at);
+        start = at + line[at..].chars().next().map_or(1, char::len_utf8);
+        if start > line.len() {
+            break;
+        }
+    }
+    matches
+}
+
+// The word in a `\<word\>` pattern, which only matches whole words
+fn whole_word(pattern: &str) -> Option<&str> {
+    pattern
+        .strip_prefix("\\<")?
+        .strip_suffix("\\>")
+        .filter(|word| !word.is_empty())
+}
+
+// Like `find_all`, comparing lowercased chars one by one so the match is
+// still as many chars long as the pattern
+fn find_all_ignore_case(line: &str, pattern: &str) -> Vec<usize> {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let chars: Vec<(usize, char)> = line.char_indices().collect();
+    if pattern.is_empty() || pattern.len() > chars.len() {
+        return Vec::new();
+    }
+    (0..=chars.len() - pattern.len())
+        .filter(|&i| {
+            chars[i..]
+                .iter()
+                .zip(&pattern)
+                .all(|(&(_, a), &b)| a.to_lowercase().eq(b.to_lowercase()))
+        })
+        .map(|i| chars[i].0)
+        .collect()
+}
+
+fn leading_whitespace(line: &str) -> &str {
+    let end = line.len() - line.trim_start_matches([' ', '\t']).len();
+    &line[..end]
+}
+
+// The indentation, bullet or number and spacing starting a list item like
+// `  - item` or `3. item`, and the same for the item after it
+fn list_marker(line: &str) -> Option<(String, String)> {
+    let indent = leading_whitespace(line);
+    let rest = &line[indent.len()..];
+    let digits = rest.len() - rest.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    let (marker, next) = if rest.starts_with(['-', '*', '+']) {
+        (&rest[..1], rest[..1].to_string())
+    } else if digits > 0 && rest[digits..].starts_with(['.', ')']) {
+        let number: u64 = rest[..digits].parse().ok()?;
+        let delimiter = &rest[digits..digits + 1];
+        (
+            &rest[..digits + 1],
+            format!("{}{}", number.checked_add(1)?, delimiter),
+        )
+    } else {
+        return None;
+    };
+    let after = &rest[marker.len()..];
+    let spacing = leading_whitespace(after);
+    if spacing.is_empty() {
+        return None;
+    }
+    Some((
+        format!("{}{}{}", indent, marker, spacing),
+        format!("{}{}{}", indent, next, spacing),
+    ))
+}
+
+// Char index of the first non-whitespace char, or the line length if blank
+fn first_non_blank(line: &str) -> usize {
+    line.chars()
+        .position(|c| !c.is_whitespace())
+        .unwrap_or_else(|| char_count(line))
+}
+
+fn char_count(line: &str) -> usize {
+    line.chars().count()
+}
+
+// Char index of `line` drawn at screen column `col`, or the line length for
+// columns past its end
+fn char_at_col(line: &str, col: usize, tab_width: usize) -> usize {
+    let mut start = 0;
+    for (i, c) in line.chars().enumerate() {
+        start += char_width(c, start, tab_width);
+        if col < start {
+            return i;
+        }
+    }
+    char_count(line)
+}
+
+// Char indices of `line` with any part inside the display columns `cols`;
+// empty when the line ends before them
+fn chars_in_cols(line: &str, cols: Range<usize>, tab_width: usize) -> Range<usize> {
+    let (mut start, mut end) = (None, 0);
+    let mut col = 0;
+    for (i, c) in line.chars().enumerate() {
+        if col >= cols.end {
+            break;
+        }
+        let w = char_width(c, col, tab_width);
+        if col + w > cols.start {
+            start.get_or_insert(i);
+            end = i + 1;
+        }
+        col += w;
+    }
+    match start {
+        Some(start) => start..end,
+        None => 0..0,
+    }
+}
+
+fn set_style(out: &mut impl Write, style: CellStyle) -> Result<()> {
+    queue!(out, SetAttribute(Attribute::Reset))?;
+    if let Some(fg) = style.fg {
+        queue!(out, SetForegroundColor(fg))?;
+    }
+    if let Some(bg) = style.bg {
+        queue!(out, SetBackgroundColor(bg))?;
+    }
+    if style.reverse {
+        queue!(out, SetAttribute(Attribute::Reverse))?;
+    }
+    if style.dim {
+        queue!(out, SetAttribute(Attribute::Dim))?;
+    }
+    Ok(())
+}
+
+// Truncate or pad `text` with spaces to exactly `width` columns
+fn fit_width(text: &str, width: usize) -> String {
+    let mut fitted = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w > width {
+            break;
+        }
+        fitted.push(c);
+        used += w;
+    }
+    fitted.push_str(&" ".repeat(width - used));
+    fitted
+}
+
+// Columns taken by `c` when drawn at column `col`; tabs run to the next stop
+fn char_width(c: char, col: usize, tab_width: usize) -> usize {
+    if c == '\t' {
+        tab_width - col % tab_width
+    } else {
+        c.width().unwrap_or(0)
+    }
+}
+
+// Where each screen row of `line` starts when wrapped to `width` columns, as
+// (char index, display column). Rows break after whitespace where they can and
+// mid-word when a word doesn't fit on a row of its own.
+fn wrap_starts(line: &str, width: usize, tab_width: usize) -> Vec<(usize, usize)> {
+    let mut starts = vec![(0, 0)];
+    let mut cols = Vec::new();
+    let mut last_break = None;
+    let mut col = 0;
+    for (i, c) in line.chars().enumerate() {
+        cols.push(col);
+        let w = char_width(c, col, tab_width);
+        let (row_start, row_col) = starts[starts.len() - 1];
+        if col + w - row_col > width && i > row_start {
+            let start = match last_break {
+                Some(b) if b > row_start && col + w - cols[b] <= width => b,
+                _ => i,
+            };
+            starts.push((start, cols[start]));
+        }
+        if c.is_whitespace() {
+            last_break = Some(i + 1);
+        }
+        col += w;
+    }
+    starts
+}
+
+// Screen column at which the `idx`-th char of `line` is drawn
+fn display_col(line: &str, idx: usize, tab_width: usize) -> usize {
+    line.chars()
+        .take(idx)
+        .fold(0, |col, c| col + char_width(c, col, tab_width))
+}
+
+// The integer a line starts with, after any indentation
+fn leading_number(line: &str) -> Option<i64> {
+    let line = line.trim_start();
+    let sign = usize::from(line.starts_with('-'));
+    let digits = line[sign..]
+        .chars()
+        .take_while(char::is_ascii_digit)
+        .count();
+    line[..sign + digits].parse().ok()
+}
+
+fn main() -> Result<()> {
+    let (config, warning) = Config::load();
+    // `-R` opens the file read-only
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let read_only = args.first().is_some_and(|arg| arg == "-R");
+    if read_only {
+        args.remove(0);
+    }
+    let mut editor = match args.first() {
+        Some(path) => match Editor::open(Path::new(&path), &config) {
+            Ok(editor) => editor,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None if !stdin().is_terminal() => match Editor::from_stdin(&config) {
+            Ok(editor) => editor,
+            Err(e) => {
+                eprintln!("stdin: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => Editor::new(&config),
+    };
+    editor.buf.read_only |= read_only;
+    if let Some(warning) = warning {
+        editor.set_status(warning);
+    }
+    editor.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn editor(text: &str) -> Editor {
+        let mut editor = Editor::new(&Config::default());
+        editor.buf.content = TextBuffer::from_text(text);
+        editor
+    }
+
+    fn press(editor: &mut Editor, code: KeyCode) {
+        editor
+            .handle_key(KeyEvent::new(code, KeyModifiers::NONE))
+            .unwrap();
+    }
+
+    fn type_keys(editor: &mut Editor, keys: &str) {
+        for c in keys.chars() {
+            press(editor, KeyCode::Char(c));
+        }
+    }
+
+    #[test]
+    fn tabs_run_to_the_next_stop() {
+        assert_eq!(char_width('\t', 0, 4), 4);
+        assert_eq!(char_width('\t', 1, 4), 3);
+        assert_eq!(char_width('\t', 3, 4), 1);
+        assert_eq!(char_width('\t', 4, 4), 4);
+        assert_eq!(char_width('\t', 5, 8), 3);
+        assert_eq!(display_col("\tx", 1, 4), 4);
+        assert_eq!(display_col("ab\tx", 3, 4), 4);
+        assert_eq!(display_col("abcd\tx", 5, 4), 8);
+        assert_eq!(display_col("a\t\tx", 3, 8), 16);
+        assert_eq!(display_col("日\tx", 2, 4), 4);
+    }
+
+    #[test]
+    fn cursor_after_a_tab_is_at_the_tab_stop() {
+        let mut editor = editor("");
+        type_keys(&mut editor, "i");
+        press(&mut editor, KeyCode::Tab);
+        assert_eq!(editor.buf.content.line(0), "\t");
+        assert_eq!(editor.buf.cursor_x, 1);
+        assert_eq!(display_col("\t", editor.buf.cursor_x, 4), 4);
+
+        type_keys(&mut editor, "x");
+        assert_eq!(editor.buf.content.line(0), "\tx");
+        assert_eq!(editor.buf.cursor_x, 2);
+        assert_eq!(display_col("\tx", editor.buf.cursor_x, 4), 5);
+    }
+
+    #[test]
+    fn deleting_every_line_leaves_the_editor_usable() {
+        let mut editor = editor("one\ntwo\nthree");
+        editor.buf.cursor_y = 2;
+        editor.buf.cursor_x = 3;
+        type_keys(&mut editor, "dgg");
+        assert_eq!(editor.buf.content.line_count(), 1);
+        assert_eq!(editor.buf.content.line(0), "");
+        assert_eq!((editor.buf.cursor_x, editor.buf.cursor_y), (0, 0));
+
+        type_keys(&mut editor, "ihi");
+        assert_eq!(editor.buf.content.line(0), "hi");
+    }
+
+    #[test]
+    fn undo_puts_the_cursor_back_where_the_edit_was() {
+        let mut editor = editor("first line\n0123456789abcdef");
+        editor.buf.cursor_y = 1;
+        editor.buf.cursor_x = 10;
+        type_keys(&mut editor, "x");
+        assert_eq!(editor.buf.content.line(1), "0123456789bcdef");
+        editor.buf.cursor_x = 0;
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buf.content.line(1), "0123456789abcdef");
+        assert_eq!((editor.buf.cursor_x, editor.buf.cursor_y), (10, 1));
+
+        type_keys(&mut editor, "dd");
+        type_keys(&mut editor, "u");
+        assert_eq!(editor.buf.content.line_count(), 2);
+        assert_eq!((editor.buf.cursor_x, editor.buf.cursor_y), (10, 1));
+        editor
+            .handle_key(KeyEvent::new(KeyCode::Char('r'), KeyModifiers::CONTROL))
+            .unwrap();
+        assert_eq!(editor.buf.content.line_count(), 1);
+        assert_eq!(editor.buf.content.line(0), "first line");
+    }
+
+    #[test]
+    fn multibyte_text_is_edited_by_char() {
+        let mut editor = editor("héllo wörld");
+        type_keys(&mut editor, "ll");
+        assert_eq!(editor.buf.cursor_x, 2);
+        type_keys(&mut editor, "x");
+        assert_eq!(editor.buf.content.line(0), "hélo wörld");
+
+        type_keys(&mut editor, "wl");
+        assert_eq!(editor.buf.cursor_x, 6);
+        type_keys(&mut editor, "x");
+        assert_eq!(editor.buf.content.line(0), "hélo wrld");
+        type_keys(&mut editor, "iö");
+        press(&mut editor, KeyCode::Esc);
+        assert_eq!(editor.buf.content.line(0), "hélo wörld");
+        assert_eq!(editor.buf.cursor_x, 6);
+
+        type_keys(&mut editor, "$");
+        assert_eq!(editor.buf.cursor_x, 9);
+        assert_eq!(display_col("hélo wörld", editor.buf.cursor_x, 4), 9);
+    }
+
+    #[test]
+    fn typing_inside_multibyte_text_does_not_split_a_char() {
+        let mut editor = editor("");
+        type_keys(&mut editor, "icafé");
+        press(&mut editor, KeyCode::Esc);
+        type_keys(&mut editor, "iX");
+        assert_eq!(editor.buf.content.line(0), "cafXé");
+        press(&mut editor, KeyCode::Backspace);
+        type_keys(&mut editor, "日");
+        assert_eq!(editor.buf.content.line(0), "caf日é");
+        assert_eq!(display_col("caf日é", editor.buf.cursor_x, 4), 5);
+    }
+
+    #[test]
+    fn numbered_lists_count_up() {
+        let marker = |prefix: &str, next: &str| Some((prefix.to_string(), next.to_string()));
+        assert_eq!(list_marker("1. x"), marker("1. ", "2. "));
+        assert_eq!(list_marker("  9) x"), marker("  9) ", "  10) "));
+        assert_eq!(list_marker("18446744073709551615. x"), None);
+    }
+
+    #[test]
+    fn enter_ends_a_list_whose_number_would_overflow() {
+        let mut editor = editor("18446744073709551615. x");
+        editor.continue_lists = true;
+        type_keys(&mut editor, "A");
+        press(&mut editor, KeyCode::Enter);
+        assert_eq!(editor.buf.content.line(0), "18446744073709551615. x");
+        assert_eq!(editor.buf.content.line(1), "");
+    }
+
+    #[test]
+    fn counts_before_and_after_an_operator_multiply() {
+        let mut words = editor("a b c d e f g h i j");
+        type_keys(&mut words, "2d3w");
+        assert_eq!(words.buf.content.line(0), "g h i j");
+
+        let mut lines = editor("one\ntwo\nthree\nfour\nfive");
+        type_keys(&mut lines, "d2d");
+        assert_eq!(lines.buf.content.line(0), "three");
+    }
+
+    #[test]
+    fn zero_after_an_operator_count_is_a_motion() {
+        let mut start = editor("hello world");
+        start.buf.cursor_x = 6;
+        type_keys(&mut start, "2d0");
+        assert_eq!(start.buf.content.line(0), "world");
+
+        let mut words = editor("a b c d e f g h i j k l");
+        type_keys(&mut words, "d10w");
+        assert_eq!(words.buf.content.line(0), "k l");
+    }
+    #[test]
+    fn paragraph_operators_take_whole_lines_from_the_line_start() {
+        let mut delete = editor("a\nb\n\nc");
+        type_keys(&mut delete, "d}");
+        assert_eq!(delete.buf.content.lines().collect::<Vec<_>>(), ["", "c"]);
+        assert_eq!((delete.buf.cursor_x, delete.buf.cursor_y), (0, 0));
+        type_keys(&mut delete, "p");
+        assert_eq!(
+            delete.buf.content.lines().collect::<Vec<_>>(),
+            ["", "a", "b", "c"]
+        );
+
+        let mut yank = editor("a\nb\n\nc");
+        yank.buf.cursor_y = 3;
+        type_keys(&mut yank, "y{");
+        assert!(matches!(&yank.register, Register::Lines(lines) if lines == &[""]));
+        assert_eq!(yank.buf.cursor_y, 2);
+    }
+
+    #[test]
+    fn paragraph_operators_stay_charwise_from_mid_line() {
+        let mut delete = editor("ab\ncd\n\ne");
+        delete.buf.cursor_x = 1;
+        type_keys(&mut delete, "d}");
+        assert_eq!(
+            delete.buf.content.lines().collect::<Vec<_>>(),
+            ["a", "", "e"]
+        );
+
+        let mut change = editor("ab\ncd\n\ne");
+        change.buf.cursor_x = 1;
+        type_keys(&mut change, "c}X");
+        assert_eq!(
+            change.buf.content.lines().collect::<Vec<_>>(),
+            ["aX", "", "e"]
+        );
+        assert_eq!(change.mode, Mode::Insert);
+    }
 }
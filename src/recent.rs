@@ -0,0 +1,55 @@
+use crate::config::config_dir;
+use std::fs;
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+// How many files the list remembers
+const RECENT_LIMIT: usize = 20;
+
+// Files opened or saved lately, newest first, one absolute path per line of
+// the state file. A missing or unreadable file is an empty list.
+pub fn load() -> Vec<PathBuf> {
+    let Some(text) = state_path().and_then(|path| fs::read_to_string(path).ok()) else {
+        return Vec::new();
+    };
+    // Anything but an absolute path is left over from a damaged file
+    text.lines()
+        .map(PathBuf::from)
+        .filter(|path| path.is_absolute())
+        .take(RECENT_LIMIT)
+        .collect()
+}
+
+// Move `path` to the front of the list
+pub fn record(path: &Path) -> Result<()> {
+    let Some(state) = state_path() else {
+        return Ok(());
+    };
+    let path = absolute(path)?;
+    let mut files = load();
+    files.retain(|file| *file != path);
+    files.insert(0, path);
+    files.truncate(RECENT_LIMIT);
+
+    let mut text = String::new();
+    for file in &files {
+        text.push_str(&file.to_string_lossy());
+        text.push('\n');
+    }
+    if let Some(dir) = state.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(state, text)
+}
+
+fn state_path() -> Option<PathBuf> {
+    Some(config_dir()?.join("recent_files"))
+}
+
+// Files that don't exist yet can't be canonicalized
+fn absolute(path: &Path) -> Result<PathBuf> {
+    match fs::canonicalize(path) {
+        Ok(path) => Ok(path),
+        Err(_) => Ok(std::env::current_dir()?.join(path)),
+    }
+}
@@ -0,0 +1,263 @@
+use crossterm::style::Color;
+use ropey::Rope;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+// A sign shown in the gutter next to a line, e.g. an `E` for an error
+// reported by a linter
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Marker {
+    pub symbol: char,
+    pub color: Color,
+}
+
+// How an edit moved lines, so state tied to lines but kept outside the
+// buffer, like folds, can follow along
+#[derive(Debug, Clone, PartialEq)]
+pub enum LineShift {
+    // `count` lines went in before line `at`
+    Inserted { at: usize, count: usize },
+    Removed(Range<usize>),
+}
+
+// The lines of a buffer, kept in a rope so edits anywhere in a large file
+// cost about the same as edits at the end. Positions are (char index, line)
+// pairs, as the editor's cursor is; lines never include their newline, and
+// there is always at least one line.
+#[derive(Clone, Default)]
+pub struct TextBuffer {
+    rope: Rope,
+    // Markers by line, moved along as lines are added and removed above them
+    markers: BTreeMap<usize, Marker>,
+    // Line moves since `take_shifts` was last called
+    shifts: Vec<LineShift>,
+}
+
+// Two buffers are equal when their text is; markers are only notes on it
+impl PartialEq for TextBuffer {
+    fn eq(&self, other: &Self) -> bool {
+        self.rope == other.rope
+    }
+}
+
+impl TextBuffer {
+    // Lines are separated by `\n`, so a trailing one starts an empty last line
+    pub fn from_text(text: &str) -> Self {
+        Self {
+            rope: Rope::from_str(text),
+            markers: BTreeMap::new(),
+            shifts: Vec::new(),
+        }
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    pub fn line(&self, y: usize) -> String {
+        let mut line = self.rope.line(y).to_string();
+        if line.ends_with('\n') {
+            line.pop();
+        }
+        line
+    }
+
+    // Length of line `y` in chars, without copying it out
+    pub fn line_len(&self, y: usize) -> usize {
+        let line = self.rope.line(y);
+        let len = line.len_chars();
+        if y + 1 < self.line_count() {
+            len - 1
+        } else {
+            len
+        }
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+        (0..self.line_count()).map(|y| self.line(y))
+    }
+
+    pub fn insert_char(&mut self, y: usize, x: usize, c: char) {
+        let idx = self.char_index(x, y);
+        self.rope.insert_char(idx, c);
+        if c == '\n' {
+            self.lines_inserted(y, x, 1);
+        }
+    }
+
+    // Insert `text` at (x, y); newlines in it split the line
+    pub fn insert(&mut self, y: usize, x: usize, text: &str) {
+        let idx = self.char_index(x, y);
+        self.rope.insert(idx, text);
+        self.lines_inserted(y, x, text.matches('\n').count());
+    }
+
+    // Text from `start` up to (not including) `end`, joined with `\n`
+    pub fn slice(&self, start: (usize, usize), end: (usize, usize)) -> String {
+        self.rope.slice(self.char_range(start, end)).to_string()
+    }
+
+    // Remove the text from `start` up to (not including) `end`
+    pub fn remove(&mut self, start: (usize, usize), end: (usize, usize)) {
+        self.rope.remove(self.char_range(start, end));
+        // The lines after the first are joined onto it
+        self.lines_removed(start.1 + 1..end.1 + 1);
+    }
+
+    pub fn set_line(&mut self, y: usize, text: &str) {
+        let len = self.line_len(y);
+        self.remove((0, y), (len, y));
+        self.insert(y, 0, text);
+    }
+
+    // Insert whole lines before line `y`, or after the last line when `y` is
+    // the line count
+    pub fn insert_lines(&mut self, y: usize, lines: &[String]) {
+        if lines.is_empty() {
+            return;
+        }
+        let text = lines.join("\n");
+        self.lines_inserted(y, 0, lines.len());
+        if y == self.line_count() {
+            let end = self.rope.len_chars();
+            self.rope.insert(end, &format!("\n{}", text));
+        } else {
+            let idx = self.rope.line_to_char(y);
+            self.rope.insert(idx, &format!("{}\n", text));
+        }
+    }
+
+    // Remove whole lines and return them; removing every line leaves a single
+    // empty one
+    pub fn remove_lines(&mut self, lines: Range<usize>) -> Vec<String> {
+        let removed = lines.clone().map(|y| self.line(y)).collect();
+        let count = self.line_count();
+        let range = if lines.end < count {
+            self.rope.line_to_char(lines.start)..self.rope.line_to_char(lines.end)
+        } else if lines.start > 0 {
+            // Take the newline ending the line before instead
+            let start = self.rope.line_to_char(lines.start) - 1;
+            start..self.rope.len_chars()
+        } else {
+            0..self.rope.len_chars()
+        };
+        self.rope.remove(range);
+        self.lines_removed(lines);
+        removed
+    }
+
+    // The whole text with `ending` between lines
+    pub fn to_string_with(&self, ending: &str) -> String {
+        if ending == "\n" {
+            return self.rope.to_string();
+        }
+        self.lines().collect::<Vec<_>>().join(ending)
+    }
+
+    pub fn set_marker(&mut self, y: usize, marker: Marker) {
+        self.markers.insert(y, marker);
+    }
+
+    pub fn clear_markers(&mut self) {
+        self.markers.clear();
+    }
+
+    pub fn marker(&self, y: usize) -> Option<Marker> {
+        self.markers.get(&y).copied()
+    }
+
+    pub fn has_markers(&self) -> bool {
+        !self.markers.is_empty()
+    }
+
+    // A copy of just the text, for undo to go back to with `replace`
+    pub fn snapshot(&self) -> TextBuffer {
+        TextBuffer {
+            rope: self.rope.clone(),
+            ..TextBuffer::default()
+        }
+    }
+
+    // Take on `other`'s text, as undo does. Markers are kept, and they and
+    // anything following the shifts move as if the lines that came or went
+    // had been edited in below the last line the two share.
+    pub fn replace(&mut self, other: TextBuffer) {
+        let (old, new) = (self.line_count(), other.line_count());
+        let mut same = 0;
+        while same < old.min(new) && self.rope.line(same) == other.rope.line(same) {
+            same += 1;
+        }
+        self.rope = other.rope;
+        if new > old {
+            self.lines_inserted(same, 0, new - old);
+        } else {
+            self.lines_removed(same..same + old - new);
+        }
+    }
+
+    // How lines have moved since the last call
+    pub fn take_shifts(&mut self) -> Vec<LineShift> {
+        std::mem::take(&mut self.shifts)
+    }
+
+    // `count` new lines went in at (x, y); a marker moves down with its
+    // line's text, so one on line `y` itself only moves when the insert was at
+    // the start of it
+    fn lines_inserted(&mut self, y: usize, x: usize, count: usize) {
+        let from = if x == 0 { y } else { y + 1 };
+        if count == 0 {
+            return;
+        }
+        self.shifts.push(LineShift::Inserted { at: from, count });
+        if self.markers.range(from..).next().is_none() {
+            return;
+        }
+        let moved = self.markers.split_off(&from);
+        self.markers.extend(
+            moved
+                .into_iter()
+                .map(|(line, marker)| (line + count, marker)),
+        );
+    }
+
+    // Markers on removed lines go, and the ones below them move up
+    fn lines_removed(&mut self, lines: Range<usize>) {
+        if lines.is_empty() {
+            return;
+        }
+        let count = lines.len();
+        self.shifts.push(LineShift::Removed(lines.clone()));
+        if self.markers.range(lines.start..).next().is_none() {
+            return;
+        }
+        let below = self.markers.split_off(&lines.end);
+        self.markers.split_off(&lines.start);
+        self.markers.extend(
+            below
+                .into_iter()
+                .map(|(line, marker)| (line - count, marker)),
+        );
+    }
+
+    fn char_index(&self, x: usize, y: usize) -> usize {
+        self.rope.line_to_char(y) + x
+    }
+
+    fn char_range(&self, start: (usize, usize), end: (usize, usize)) -> Range<usize> {
+        self.char_index(start.0, start.1)..self.char_index(end.0, end.1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Paths go through `super` as the benchmark pulls this file in too, where
+    // a `use` would go unused
+    #[test]
+    fn removing_every_line_leaves_one_empty_line() {
+        let mut text = super::TextBuffer::from_text("one\ntwo\nthree");
+        let removed = text.remove_lines(0..3);
+        assert_eq!(removed, ["one", "two", "three"]);
+        assert_eq!(text.line_count(), 1);
+        assert_eq!(text.line(0), "");
+    }
+}